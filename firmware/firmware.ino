@@ -2,6 +2,12 @@ enum Command : uint8_t {
     Command_Read = 0x00,
     Command_Write = 0x01,
     Command_Verify = 0x02,
+    Command_Erase = 0x03,
+    Command_Version = 0x09,
+    Command_Limits = 0x0A,
+    // Does nothing, so that the board sends Ready again
+    Command_Nop = 0x0F,
+    Command_Negotiate = 0xFE,
 };
 
 enum Opcode : uint8_t {
@@ -13,8 +19,34 @@ enum Opcode : uint8_t {
     Opcode_InvalidChecksum = 0x05,
     Opcode_ByteMismatch = 0x06,
     Opcode_ByteRequest = 0x07,
+    Opcode_Error = 0x08,
+    Opcode_Ack = 0x09,
+    Opcode_FirmwareVersion = 0x0A,
+    Opcode_VersionOk = 0x0B,
+    Opcode_VersionMismatch = 0x0C,
+    Opcode_FirmwareLimits = 0x0D,
 };
 
+// Bits of the first byte following Command_Write
+enum WriteFlag : uint8_t {
+    WriteFlag_Verify = 0x01,
+    WriteFlag_PageWrite = 0x02,
+};
+
+enum ErrorCode : uint8_t {
+    ErrorCode_AddressOutOfRange = 0x01,
+    ErrorCode_WriteProtected = 0x02,
+    ErrorCode_HardwareFault = 0x03,
+};
+
+constexpr uint8_t VERSION_MAJOR = 1;
+constexpr uint8_t VERSION_MINOR = 3;
+constexpr uint8_t VERSION_PATCH = 0;
+
+constexpr uint16_t PROTOCOL_VERSION = 3;
+
+constexpr uint32_t ADDRESS_SPACE = 0x8000;
+
 constexpr size_t DELAY_TIME = 2;
 
 constexpr uint8_t CHIP_ENABLE = 50;
@@ -67,6 +99,23 @@ void write_data_careful(const uint16_t addr, const uint8_t value)
     delayMicroseconds(50);
 }
 
+// Loads a whole page with short WE pulses and lets the chip program it in a
+// single write cycle. The bytes must not cross a page boundary.
+void write_page(const uint16_t addr, const uint8_t *data, const uint8_t len)
+{
+    DDRL = B11111111;
+
+    for (uint8_t i = 0; i < len; ++i) {
+        set_address(addr + i);
+        PORTL = data[i];
+
+        digitalWrite(WRITE_ENABLE, LOW);
+        digitalWrite(WRITE_ENABLE, HIGH);
+    }
+
+    delay(10);
+}
+
 void print_u16(const uint16_t x)
 {
     if (x < 0x10)
@@ -119,8 +168,30 @@ void serial_print(const char str[])
     Serial.print(str);
 }
 
+void serial_error(const ErrorCode code, const char message[])
+{
+    Serial.write(Opcode_Error);
+    Serial.write(code);
+
+    const uint8_t len = strlen(message);
+    Serial.write(len);
+    Serial.print(message);
+}
+
 const uint8_t CHUNK_ACK = 0xFF;
-constexpr size_t CHUNK_SIZE = 32;
+const uint8_t CHUNK_SKIP = 0xFE;
+const uint8_t SKIP_PAGE = 0xFE;
+const uint8_t SET_ADDRESS = 0xFD;
+const uint8_t ABORT = 0xFC;
+constexpr size_t DEFAULT_CHUNK_SIZE = 32;
+constexpr size_t MAX_CHUNK_SIZE = 0xFF;
+
+// Largest chunk a command may carry, as bigger sizes mark skips, address
+// changes and aborts
+constexpr uint8_t MAX_DATA_CHUNK_SIZE = ABORT - 1;
+// Largest page write_page loads before the write cycle starts. A page is
+// sent as a single chunk.
+constexpr uint8_t MAX_PAGE_SIZE = MAX_DATA_CHUNK_SIZE;
 
 uint16_t calculate_checksum(const uint8_t data[], const size_t len)
 {
@@ -135,52 +206,61 @@ uint16_t calculate_checksum(const uint8_t data[], const size_t len)
     return ((uint16_t)sum_2 << 8) | (uint16_t)sum_1;
 }
 
-void read_eeprom(const uint16_t start, const uint16_t end)
+// Checks whether the uploader gave up on the chunk being read
+bool skip_requested()
 {
-    const size_t chunk_count = (end - start) / CHUNK_SIZE;
+    if (Serial.available() == 0 || Serial.peek() != CHUNK_SKIP)
+        return false;
 
-    for (size_t c = 0; c < chunk_count; ++c) {
+    Serial.read();
+    return true;
+}
 
-        const uint16_t chunk_start = start + (c * CHUNK_SIZE);
-        const uint16_t chunk_end = chunk_start + CHUNK_SIZE;
+void send_chunk(const uint16_t chunk_start, const uint8_t size)
+{
+    uint8_t chunk[MAX_CHUNK_SIZE] = {};
 
-        uint8_t chunk[CHUNK_SIZE] = {};
+    for (uint16_t i = 0; i < size; ++i) {
+        if (skip_requested())
+            return;
 
-        for (uint16_t i = 0; i < CHUNK_SIZE; ++i)
-            chunk[i] = read_data(chunk_start + i);
+        chunk[i] = read_data(chunk_start + i);
+    }
 
-        Serial.write(Opcode_Chunk);
-        Serial.write(CHUNK_SIZE);
-        serial_write_u16(calculate_checksum(chunk, CHUNK_SIZE));
+    Serial.write(Opcode_Chunk);
+    Serial.write(size);
+    serial_write_u16(calculate_checksum(chunk, size));
 
-        Serial.write(chunk, CHUNK_SIZE);
+    Serial.write(chunk, size);
 
-        // Wait for chunk ACK
-        while (serial_read_u8() != CHUNK_ACK)
-            ;
-    }
+    // Wait for chunk ACK
+    while (true) {
+        const uint8_t ack = serial_read_u8();
 
-    const uint8_t remaining_bytes = (end - start) % CHUNK_SIZE;
+        if (ack == CHUNK_ACK || ack == CHUNK_SKIP)
+            break;
+    }
+}
 
-    if (remaining_bytes != 0) {
-        const uint16_t remainder_start = end - remaining_bytes;
+void read_eeprom(const uint16_t start, const uint16_t end, size_t chunk_size)
+{
+    if (chunk_size == 0)
+        chunk_size = DEFAULT_CHUNK_SIZE;
 
-        uint8_t chunk[CHUNK_SIZE] = {};
+    const size_t chunk_count = (end - start) / chunk_size;
 
-        for (uint16_t i = 0; i < remaining_bytes; ++i)
-            chunk[i] = read_data(remainder_start + i);
+    for (size_t c = 0; c < chunk_count; ++c)
+        send_chunk(start + (c * chunk_size), chunk_size);
 
-        Serial.write(Opcode_Chunk);
-        Serial.write(remaining_bytes);
-        serial_write_u16(calculate_checksum(chunk, remaining_bytes));
+    const uint8_t remaining_bytes = (end - start) % chunk_size;
 
-        Serial.write(chunk, remaining_bytes);
-    }
+    if (remaining_bytes != 0)
+        send_chunk(end - remaining_bytes, remaining_bytes);
 
     Serial.write(Opcode_ReadEnd);
 }
 
-void write_eeprom(const bool verify)
+void write_eeprom(const bool verify, const bool page_write, const bool fix)
 {
     uint16_t addr = 0;
     uint8_t chunk[0x100];
@@ -193,6 +273,21 @@ void write_eeprom(const bool verify)
         if (chunk_size == 0)
             break;
 
+        if (chunk_size == ABORT) {
+            Serial.write(Opcode_Ack);
+            return;
+        }
+
+        if (chunk_size == SKIP_PAGE) {
+            addr += serial_read_u8();
+            continue;
+        }
+
+        if (chunk_size == SET_ADDRESS) {
+            addr = serial_read_u16();
+            continue;
+        }
+
         const uint16_t checksum = serial_read_u16();
 
         while (Serial.available() < chunk_size)
@@ -203,10 +298,22 @@ void write_eeprom(const bool verify)
             calculate_checksum(chunk, chunk_size);
 
         if (checksum != computed_checksum) {
+            // Drop the chunk and ask for it again
             Serial.write(Opcode_InvalidChecksum);
             serial_write_u16(checksum);
             serial_write_u16(computed_checksum);
-            break;
+            continue;
+        }
+
+        if ((uint32_t)addr + chunk_size > ADDRESS_SPACE) {
+            serial_error(ErrorCode_AddressOutOfRange, "address out of range");
+            return;
+        }
+
+        if (page_write) {
+            write_page(addr, chunk, chunk_size);
+            addr += chunk_size;
+            continue;
         }
 
         for (uint16_t i = 0; i < chunk_size; ++i) {
@@ -216,7 +323,7 @@ void write_eeprom(const bool verify)
     }
 
     if (verify)
-        verify_eeprom(true);
+        verify_eeprom(fix);
 }
 
 void verify_eeprom(const bool fix)
@@ -233,6 +340,16 @@ void verify_eeprom(const bool fix)
         if (chunk_size == 0)
             break;
 
+        if (chunk_size == ABORT) {
+            Serial.write(Opcode_Ack);
+            return;
+        }
+
+        if (chunk_size == SET_ADDRESS) {
+            addr = serial_read_u16();
+            continue;
+        }
+
         const uint16_t checksum = serial_read_u16();
 
         while (Serial.available() < chunk_size)
@@ -246,7 +363,7 @@ void verify_eeprom(const bool fix)
             Serial.write(Opcode_InvalidChecksum);
             serial_write_u16(checksum);
             serial_write_u16(computed_checksum);
-            break;
+            continue;
         }
 
         for (uint16_t i = 0; i < chunk_size; ++i) {
@@ -282,6 +399,45 @@ void verify_eeprom(const bool fix)
     }
 }
 
+// Erases addresses [0, end) a page at a time, so that a whole page takes a
+// single write cycle. Chips without page mode (page_size of 1) are erased a
+// byte at a time with the shorter byte write cycle.
+void erase_eeprom(const uint16_t end, const uint8_t page_size)
+{
+    static uint8_t blank[0xFF];
+    memset(blank, 0xFF, sizeof(blank));
+
+    if (page_size <= 1) {
+        for (uint32_t addr = 0; addr < end; addr++) {
+            write_data(addr, 0xFF);
+        }
+    } else {
+        for (uint32_t addr = 0; addr < end; addr += page_size) {
+            const uint32_t remaining = end - addr;
+            write_page(addr, blank,
+                       remaining < page_size ? remaining : page_size);
+        }
+    }
+
+    Serial.write(Opcode_Ack);
+}
+
+void negotiate()
+{
+    // Acknowledge first, so the host only sends its version to firmware that
+    // knows to read it
+    Serial.write(Opcode_Ack);
+
+    const uint16_t host_version = serial_read_u16();
+
+    if (host_version == PROTOCOL_VERSION) {
+        Serial.write(Opcode_VersionOk);
+    } else {
+        Serial.write(Opcode_VersionMismatch);
+        serial_write_u16(PROTOCOL_VERSION);
+    }
+}
+
 void setup()
 {
     Serial.begin(115200);
@@ -294,12 +450,17 @@ void setup()
 
     digitalWrite(OUTPUT_ENABLE, HIGH);
     digitalWrite(WRITE_ENABLE, HIGH);
-    digitalWrite(CHIP_ENABLE, LOW);
+    digitalWrite(CHIP_ENABLE, HIGH);
 
     while (!Serial)
         ;
 
     delay(50);
+}
+
+void loop()
+{
+    digitalWrite(CHIP_ENABLE, LOW);
     Serial.write(Opcode_Ready);
 
     const uint8_t command = serial_read_u8();
@@ -308,12 +469,21 @@ void setup()
     case Command_Read: {
         const uint16_t start = serial_read_u16();
         const uint16_t end = serial_read_u16();
-        read_eeprom(start, end);
+        const uint8_t chunk_size = serial_read_u8();
+
+        if (end < start || end > ADDRESS_SPACE) {
+            serial_error(ErrorCode_AddressOutOfRange, "address out of range");
+            break;
+        }
+
+        read_eeprom(start, end, chunk_size);
         break;
     }
     case Command_Write: {
-        const bool verify = serial_read_u8();
-        write_eeprom(true);
+        const uint8_t flags = serial_read_u8();
+        const bool fix = serial_read_u8();
+        write_eeprom(flags & WriteFlag_Verify, flags & WriteFlag_PageWrite,
+                     fix);
         break;
     }
     case Command_Verify: {
@@ -321,11 +491,35 @@ void setup()
         verify_eeprom(fix);
         break;
     }
+    case Command_Erase: {
+        const uint16_t end = serial_read_u16();
+        const uint8_t page_size = serial_read_u8();
+
+        if (end > ADDRESS_SPACE) {
+            serial_error(ErrorCode_AddressOutOfRange, "address out of range");
+            break;
+        }
+
+        erase_eeprom(end, page_size);
+        break;
+    }
+    case Command_Version:
+        Serial.write(Opcode_FirmwareVersion);
+        Serial.write(VERSION_MAJOR);
+        Serial.write(VERSION_MINOR);
+        Serial.write(VERSION_PATCH);
+        break;
+    case Command_Limits:
+        Serial.write(Opcode_FirmwareLimits);
+        Serial.write(MAX_DATA_CHUNK_SIZE);
+        Serial.write(MAX_PAGE_SIZE);
+        break;
+    case Command_Nop:
+        break;
+    case Command_Negotiate:
+        negotiate();
+        break;
     }
 
     digitalWrite(CHIP_ENABLE, HIGH);
 }
-
-void loop()
-{
-}
@@ -0,0 +1,46 @@
+use std::io::Write;
+
+/// Bytes put on each line of the array.
+const LINE_SIZE: usize = 16;
+
+/// Whether `name` can be used as a C identifier.
+pub fn is_valid_symbol(name: &str) -> bool {
+    let mut chars = name.chars();
+
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Opens a header declaring `symbol` as an array of `size` bytes.
+pub fn write_start(out: &mut impl Write, size: usize, symbol: &str) -> std::io::Result<()> {
+    writeln!(out, "#pragma once")?;
+    writeln!(out)?;
+    writeln!(out, "#include <stdint.h>")?;
+    writeln!(out)?;
+    writeln!(out, "#define EEPROM_SIZE {}", size)?;
+    writeln!(out)?;
+    writeln!(out, "const uint8_t {}[EEPROM_SIZE] = {{", symbol)
+}
+
+/// Writes `data` as array elements, with a comment holding the address of
+/// the first byte on each line.
+pub fn write_data(out: &mut impl Write, address: u16, data: &[u8]) -> std::io::Result<()> {
+    for (i, line) in data.chunks(LINE_SIZE).enumerate() {
+        write!(out, "   ")?;
+
+        for byte in line {
+            write!(out, " 0x{:02X},", byte)?;
+        }
+
+        let address = address.wrapping_add((i * LINE_SIZE) as u16);
+        writeln!(out, " // 0x{:04X}", address)?;
+    }
+
+    Ok(())
+}
+
+pub fn write_end(out: &mut impl Write) -> std::io::Result<()> {
+    writeln!(out, "}};")
+}
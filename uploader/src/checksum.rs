@@ -0,0 +1,118 @@
+use std::{fmt, str::FromStr};
+
+use md5::Md5;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumAlgorithm {
+    Sha256,
+    Md5,
+    Crc32,
+    Fletcher16,
+}
+
+impl ChecksumAlgorithm {
+    /// Computes the checksum of `data` as a lowercase hex string.
+    pub fn compute(self, data: &[u8]) -> String {
+        match self {
+            Self::Sha256 => to_hex(&Sha256::digest(data)),
+            Self::Md5 => to_hex(&Md5::digest(data)),
+            Self::Crc32 => format!("{:08x}", crc32fast::hash(data)),
+            Self::Fletcher16 => format!("{:04x}", fletcher16(data)),
+        }
+    }
+}
+
+impl fmt::Display for ChecksumAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Sha256 => "sha256",
+            Self::Md5 => "md5",
+            Self::Crc32 => "crc32",
+            Self::Fletcher16 => "fletcher16",
+        };
+
+        f.write_str(name)
+    }
+}
+
+impl FromStr for ChecksumAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "sha256" => Ok(Self::Sha256),
+            "md5" => Ok(Self::Md5),
+            "crc32" => Ok(Self::Crc32),
+            "fletcher16" => Ok(Self::Fletcher16),
+            _ => Err(format!(
+                "unknown checksum algorithm '{}' (expected sha256, md5, crc32 or fletcher16)",
+                s
+            )),
+        }
+    }
+}
+
+/// An expected checksum, given as `<algorithm>:<hex>`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct InputChecksum {
+    pub algorithm: ChecksumAlgorithm,
+    pub expected: String,
+}
+
+impl InputChecksum {
+    /// Returns the computed checksum if it does not match the expected one.
+    pub fn check(&self, data: &[u8]) -> Result<(), String> {
+        let computed = self.algorithm.compute(data);
+
+        if computed.eq_ignore_ascii_case(&self.expected) {
+            Ok(())
+        } else {
+            Err(computed)
+        }
+    }
+}
+
+impl FromStr for InputChecksum {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (algorithm, expected) = s
+            .split_once(':')
+            .ok_or_else(|| "expected <algorithm>:<hex>".to_owned())?;
+
+        if expected.is_empty() || !expected.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("'{}' is not a hex string", expected));
+        }
+
+        Ok(Self {
+            algorithm: algorithm.parse()?,
+            expected: expected.to_owned(),
+        })
+    }
+}
+
+impl TryFrom<String> for InputChecksum {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn fletcher16(data: &[u8]) -> u16 {
+    let (mut sum1, mut sum2) = (0u16, 0u16);
+
+    for &byte in data {
+        sum1 = (sum1 + u16::from(byte)) % 255;
+        sum2 = (sum2 + sum1) % 255;
+    }
+
+    (sum2 << 8) | sum1
+}
@@ -0,0 +1,142 @@
+use std::{str::FromStr, time::Duration};
+
+use serde::Deserialize;
+
+/// Bytes the board can address with its 15 address lines, which is also the
+/// capacity of the largest chip it supports.
+pub const EEPROM_CAPACITY: u32 = 32 * 1024;
+
+/// Size and timing of an EEPROM model, as given by its datasheet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChipSpec {
+    pub capacity_bytes: u32,
+    /// Bytes that can be written in a single write cycle.
+    pub page_size: u16,
+    /// Longest a write cycle can take.
+    pub write_cycle_us: u32,
+}
+
+/// EEPROM models that fit in the board's 15 address lines. Bigger AT28C parts
+/// such as the AT28C010 need more address lines than the board has, and more
+/// addresses than the protocol's 16-bit ones can reach, so they are left out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Chip {
+    At28c16,
+    At28c64,
+    #[default]
+    At28c256,
+}
+
+impl ChipSpec {
+    /// Longest the board may take to erase the whole chip, a write cycle per
+    /// page.
+    pub fn erase_time(self) -> Duration {
+        let cycles = self.capacity_bytes.div_ceil(self.page_size.max(1).into());
+        Duration::from_micros(u64::from(cycles) * u64::from(self.write_cycle_us))
+    }
+}
+
+impl Chip {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::At28c16 => "AT28C16",
+            Self::At28c64 => "AT28C64",
+            Self::At28c256 => "AT28C256",
+        }
+    }
+
+    pub fn spec(self) -> ChipSpec {
+        match self {
+            // Has no page mode, so every byte takes a full write cycle
+            Self::At28c16 => ChipSpec {
+                capacity_bytes: 2 * 1024,
+                page_size: 1,
+                write_cycle_us: 1_000,
+            },
+            Self::At28c64 => ChipSpec {
+                capacity_bytes: 8 * 1024,
+                page_size: 64,
+                write_cycle_us: 10_000,
+            },
+            Self::At28c256 => ChipSpec {
+                capacity_bytes: EEPROM_CAPACITY,
+                page_size: 64,
+                write_cycle_us: 10_000,
+            },
+        }
+    }
+
+    /// One past the chip's last address.
+    pub fn end_address(self) -> u16 {
+        self.spec().capacity_bytes.try_into().unwrap()
+    }
+}
+
+impl FromStr for Chip {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "at28c16" => Ok(Self::At28c16),
+            "at28c64" => Ok(Self::At28c64),
+            "at28c256" => Ok(Self::At28c256),
+            "at28c010" | "at28c020" | "at28c040" => Err(format!(
+                "chip '{}' is larger than the {} KiB the board can address",
+                s,
+                EEPROM_CAPACITY / 1024
+            )),
+            _ => Err(format!(
+                "unknown chip '{}' (expected at28c16, at28c64 or at28c256)",
+                s
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHIPS: [Chip; 3] = [Chip::At28c16, Chip::At28c64, Chip::At28c256];
+
+    #[test]
+    fn chips_fit_in_the_address_space() {
+        for chip in CHIPS {
+            assert!(
+                chip.spec().capacity_bytes <= EEPROM_CAPACITY,
+                "{}",
+                chip.name()
+            );
+        }
+    }
+
+    #[test]
+    fn erase_takes_a_write_cycle_per_page() {
+        assert_eq!(
+            Chip::At28c16.spec().erase_time(),
+            Duration::from_millis(2048)
+        );
+        assert_eq!(
+            Chip::At28c64.spec().erase_time(),
+            Duration::from_millis(1280)
+        );
+        assert_eq!(
+            Chip::At28c256.spec().erase_time(),
+            Duration::from_millis(5120)
+        );
+    }
+
+    #[test]
+    fn parses_chip_names() {
+        for chip in CHIPS {
+            assert_eq!(chip.name().parse::<Chip>(), Ok(chip));
+        }
+    }
+
+    #[test]
+    fn rejects_chips_too_big_for_the_board() {
+        let err = "at28c010".parse::<Chip>().unwrap_err();
+        assert!(err.contains("32 KiB"), "{}", err);
+    }
+}
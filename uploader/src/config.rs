@@ -0,0 +1,120 @@
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{chips::Chip, core::UserOptions, error::ConfigError};
+
+/// Commented template for the defaults file, with every setting left at its
+/// built-in value.
+pub const CONFIG_TEMPLATE: &str = r#"# Defaults for eeprom-programmer. Options given on the command line take
+# precedence over the ones set here.
+
+# Port the board is connected to, or tcp://HOST:PORT
+# port = "/dev/ttyUSB0"
+
+# Baud rate for the connection
+# baud_rate = 115200
+
+# Timeout (in milliseconds) for connecting to the board
+# timeout = 10
+
+# Bytes sent to the board per chunk when writing or verifying (1-251)
+# chunk_size = 16
+
+# EEPROM model in the socket (at28c16, at28c64 or at28c256)
+# chip = "at28c256"
+"#;
+
+/// Defaults for the command line arguments, read from a TOML file.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub port: Option<String>,
+    pub baud_rate: Option<u32>,
+    /// Connection timeout in milliseconds.
+    pub timeout: Option<u64>,
+    pub chunk_size: Option<u8>,
+    pub chip: Option<Chip>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        Ok(toml::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    /// Loads the file at [`default_path`](Self::default_path), or nothing if
+    /// there is none.
+    pub fn load_default() -> Result<Self, ConfigError> {
+        match Self::default_path() {
+            Some(path) if path.is_file() => Self::load(&path),
+            _ => Ok(Self::default()),
+        }
+    }
+
+    /// `eeprom-programmer/config.toml` in the user's config directory.
+    pub fn default_path() -> Option<PathBuf> {
+        let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+
+        Some(config_dir.join("eeprom-programmer").join("config.toml"))
+    }
+}
+
+/// Fields that have no sensible default, for each command.
+const REQUIRED_COMMAND_FIELDS: &[(&str, &[&str])] = &[
+    ("read", &["out_filename"]),
+    ("write", &["in_filename"]),
+    ("verify", &["in_filename"]),
+];
+
+impl UserOptions {
+    /// Loads the options from a JSON file, or from a TOML file if the
+    /// extension is not `.json`.
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let contents = std::fs::read_to_string(path)?;
+
+        let value: Value = if path.extension().is_some_and(|ext| ext == "json") {
+            serde_json::from_str(&contents)?
+        } else {
+            toml::from_str(&contents)?
+        };
+
+        check_required_fields(&value)?;
+
+        let opts: Self = serde_json::from_value(value)?;
+        opts.validate()?;
+
+        Ok(opts)
+    }
+}
+
+fn check_required_fields(value: &Value) -> Result<(), ConfigError> {
+    let command = value
+        .get("command")
+        .ok_or_else(|| ConfigError::MissingField("command".to_owned()))?;
+
+    let Some(command) = command.as_object() else {
+        return Ok(());
+    };
+
+    for (name, fields) in command {
+        let required = REQUIRED_COMMAND_FIELDS
+            .iter()
+            .find(|(command_name, _)| command_name == name)
+            .map_or(&[][..], |(_, required)| required);
+
+        for field in required {
+            if fields.get(field).is_none() {
+                return Err(ConfigError::MissingField(format!(
+                    "command.{}.{}",
+                    name, field
+                )));
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -1,439 +1,3536 @@
-use std::{fs::File, io::Write, path::PathBuf};
+use std::{
+    borrow::Cow,
+    collections::VecDeque,
+    fs::File,
+    io::{Cursor, Write},
+    iter,
+    ops::Range,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, Instant},
+};
 
-use derive_more::{Display, Error, From};
+use serde::{Deserialize, Deserializer};
 
 use crate::{
-    protocol::{self, Packet, ProtocolError},
-    serial::SerialIO,
+    c_header,
+    checksum::InputChecksum,
+    chips::{Chip, EEPROM_CAPACITY},
+    display,
+    error::Error,
+    intel_hex, mismatch_report,
+    protocol::{self, Packet, ProtocolError, RunningChecksum},
+    serial::{self, SerialIO},
+    srec,
+    version::{FirmwareVersion, VersionReq},
 };
 
-#[derive(Debug, From, Display, Error)]
-pub enum Error {
-    #[display("I/O error: {_0}")]
-    IO(#[from] std::io::Error),
+/// Size of the EEPROM's address space, in bytes.
+pub const ADDRESS_SPACE: usize = EEPROM_CAPACITY as usize;
 
-    Protocol(#[from] ProtocolError),
+const READ_BACK_CHUNK_SIZE: u8 = 32;
 
-    #[display("Board sent an invalid opcode: {_0}")]
-    InvalidOpcode(#[error(not(source))] u8),
+/// How long the board may take to answer protocol negotiation.
+///
+/// Firmware that stays silent is not assumed to be older firmware that can
+/// carry on without negotiating. The original firmware runs a single command
+/// from `setup()`, so once it swallows the Negotiate byte it never answers
+/// again until it is reset.
+const NEGOTIATION_TIMEOUT: Duration = Duration::from_millis(100);
 
-    #[display("Received an unexpected packet (state: {state_kind:?}, packet: {packet})")]
-    UnexpectedPacket {
-        state_kind: StateKind,
-        packet: Packet,
-    },
+/// Chunk size the firmware uses when asked for a chunk size of 0.
+const DEFAULT_CHUNK_SIZE: u8 = 32;
 
-    #[display("Checksum mismatch (expected = 0x{expected:04X}, computed = 0x{computed:04X})")]
-    ChecksumMismatch {
-        expected: u16,
-        computed: u16,
-    },
+/// Bytes sent to the board per chunk when writing or verifying.
+pub const DEFAULT_WRITE_CHUNK_SIZE: u8 = 16;
 
-    #[display("Memory region bounds must be valid")]
-    InvalidRegionBounds,
+/// Milliseconds the board gets by default to answer the command that starts
+/// a read, write or verify.
+pub const DEFAULT_START_TIMEOUT_MS: u64 = 5000;
 
-    #[display("Unknown: {_0}")]
-    Unknown(#[from] anyhow::Error),
-}
+/// Byte written in place of data that could not be read.
+const RECOVERY_PLACEHOLDER: u8 = 0xFE;
+
+/// Tells the firmware to give up on the chunk it is currently reading.
+const CHUNK_SKIP: u8 = 0xFE;
 
-#[derive(Debug, Clone)]
+/// How long the board must stay silent before a timed out chunk is skipped,
+/// long enough for a chunk that was already on its way to arrive in full.
+const RECOVERY_QUIET_TIME: Duration = Duration::from_millis(20);
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum UserCommand {
     Read {
         out_filename: PathBuf,
+        #[serde(default)]
         start: u16,
+        #[serde(default = "default_end")]
         end: u16,
+        #[serde(default = "default_chunk_size")]
+        chunk_size: u8,
+        #[serde(default)]
+        write_back: Option<String>,
+        #[serde(default)]
+        format: FileFormat,
+        #[serde(default = "default_record_size")]
+        record_size: u8,
+        #[serde(default = "default_symbol_name")]
+        symbol_name: String,
+        #[serde(default)]
+        prepend_bom: bool,
+        #[serde(default)]
+        srec_record_type: SrecRecordType,
     },
     Write {
         in_filename: PathBuf,
+        #[serde(default = "default_true")]
         verify: bool,
+        #[serde(default)]
+        verify_with_read: bool,
+        #[serde(default)]
+        write_once: bool,
+        #[serde(default)]
+        skip: usize,
+        #[serde(default)]
+        trim: Option<usize>,
+        #[serde(default)]
+        checksum: Option<InputChecksum>,
+        #[serde(default = "default_true")]
+        fix: bool,
+        #[serde(default)]
+        only_data_pages: bool,
+        #[serde(default)]
+        order: WriteOrder,
+        #[serde(default)]
+        spot_checks: Vec<SpotCheck>,
+        #[serde(default)]
+        erase_before_write: bool,
+        #[serde(default)]
+        format: FileFormat,
+        #[serde(default)]
+        offset: u16,
+        #[serde(default)]
+        extra_files: Vec<InputSegment>,
+        #[serde(default)]
+        start: u16,
+        #[serde(default)]
+        end: Option<u16>,
+        #[serde(default)]
+        dry_run: bool,
+        #[serde(default)]
+        verify_only: bool,
+        #[serde(default)]
+        page_write: bool,
     },
     Verify {
         in_filename: PathBuf,
+        #[serde(default)]
         fix: bool,
+        #[serde(default)]
+        collect_mismatches: Option<usize>,
+        #[serde(default)]
+        report: Option<PathBuf>,
     },
-}
-
-#[derive(Debug, Clone)]
-pub struct UserOptions {
-    pub command: UserCommand,
-}
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub enum StateKind {
-    Idle,
-    Reading,
-    Writing,
-    Verifying,
-    Fixing,
-    Finished,
-}
-
-#[derive(Debug, Clone)]
-pub enum Effect {
-    Print(String),
-    PrintLn(String),
-    Progress {
-        done: usize,
-        total: usize,
+    BlankCheck {
+        #[serde(default)]
+        start: u16,
+        #[serde(default = "default_end")]
+        end: u16,
+        #[serde(default = "default_report_limit")]
+        report_limit: usize,
     },
-    VerifyProgress {
-        done: usize,
-        total: usize,
-        mismatches: usize,
+    Fill {
+        #[serde(default = "default_fill_value")]
+        value: u8,
+        #[serde(default)]
+        start: u16,
+        #[serde(default = "default_end")]
+        end: u16,
+        #[serde(default = "default_true")]
+        verify: bool,
     },
-    ProgressEnd,
+    CheckFirmware,
+    FirmwareVersion,
 }
 
-#[derive(Debug, Clone)]
-pub struct ByteMismatch {
-    address: u16,
-    expected: u8,
-}
+impl UserCommand {
+    /// EEPROM address that the data being written or verified starts at.
+    fn base_address(&self) -> usize {
+        match self {
+            UserCommand::Write { start, .. } | UserCommand::Fill { start, .. } => {
+                usize::from(*start)
+            }
+            _ => 0,
+        }
+    }
 
-#[derive(Debug)]
-pub enum State {
-    Idle,
-    Reading {
-        progress: usize,
-        total: usize,
-        out_file: File,
-        out_path: PathBuf,
-    },
-    Writing {
-        current_byte: usize,
-        data: Vec<u8>,
-        verify: bool,
-    },
-    Verifying {
-        current_byte: usize,
-        data: Vec<u8>,
-        mismatches: Vec<ByteMismatch>,
-        fix: bool,
-    },
-    Fixing {
-        mismatches: Vec<ByteMismatch>,
-        current: usize,
-    },
-    Finished(Result<(), Error>),
-}
+    /// Whether mismatches found while verifying a write should be fixed.
+    fn fixes_after_write(&self) -> bool {
+        !matches!(self, UserCommand::Write { fix: false, .. })
+    }
 
-impl State {
-    pub fn kind(&self) -> StateKind {
+    fn file_format(&self) -> FileFormat {
         match self {
-            Self::Idle => StateKind::Idle,
-            Self::Reading { .. } => StateKind::Reading,
-            Self::Writing { .. } => StateKind::Writing,
-            Self::Verifying { .. } => StateKind::Verifying,
-            Self::Fixing { .. } => StateKind::Fixing,
-            Self::Finished(_) => StateKind::Finished,
+            UserCommand::Read { format, .. } | UserCommand::Write { format, .. } => *format,
+            _ => FileFormat::default(),
         }
     }
 
-    pub fn transition(
-        self,
-        packet: Packet,
-        port: &mut impl SerialIO,
-        opts: &UserOptions,
-    ) -> Result<(State, Vec<Effect>), Error> {
-        let mut effects = vec![];
+    /// Data bytes per record when dumping to a text format.
+    fn record_size(&self) -> u8 {
+        match self {
+            UserCommand::Read { record_size, .. } => *record_size,
+            _ => default_record_size(),
+        }
+    }
 
-        let next_state = match (self, packet) {
-            (_, Packet::Ready) => match opts.command {
-                UserCommand::Read {
-                    ref out_filename,
-                    start,
-                    end,
-                } => {
-                    if end < start {
-                        return Err(Error::InvalidRegionBounds);
-                    }
+    fn srec_record_type(&self) -> SrecRecordType {
+        match self {
+            UserCommand::Read {
+                srec_record_type, ..
+            } => *srec_record_type,
+            _ => SrecRecordType::default(),
+        }
+    }
 
-                    let out_file = File::create(out_filename)?;
+    /// Whether chunks are written as whole EEPROM pages, each in a single
+    /// write cycle.
+    fn page_write(&self) -> bool {
+        matches!(
+            self,
+            UserCommand::Write {
+                page_write: true,
+                ..
+            }
+        )
+    }
 
-                    effects.push(Effect::PrintLn("Initiating EEPROM read...".to_owned()));
+    fn write_order(&self) -> WriteOrder {
+        match self {
+            UserCommand::Write { order, .. } => *order,
+            _ => WriteOrder::default(),
+        }
+    }
 
-                    port.write_u8(0x00)?;
-                    port.write_u16(start)?;
-                    port.write_u16(end)?;
+    /// State to move to once a write, including its verification, is done.
+    /// Writes report how many bytes they wrote, verified and fixed first.
+    fn after_write(
+        &self,
+        written: usize,
+        verified: usize,
+        fixed: usize,
+        emit: &mut dyn FnMut(Effect),
+    ) -> State {
+        match self {
+            UserCommand::Write { verify_only, .. } => emit(Effect::WriteSummary {
+                written: if *verify_only { 0 } else { written },
+                verified,
+                fixed,
+            }),
+            UserCommand::Fill { .. } => emit(Effect::WriteSummary {
+                written,
+                verified,
+                fixed,
+            }),
+            _ => {}
+        }
 
-                    State::Reading {
-                        progress: 0,
-                        total: (end - start).into(),
-                        out_file,
-                        out_path: out_filename.clone(),
-                    }
+        match self {
+            UserCommand::Write { spot_checks, .. } if !spot_checks.is_empty() => {
+                State::AwaitingSpotCheck {
+                    checks: spot_checks.iter().cloned().collect(),
+                    failed: 0,
                 }
-                UserCommand::Write {
-                    ref in_filename,
-                    verify,
-                } => {
-                    effects.push(Effect::PrintLn("Initiating EEPROM write...".to_owned()));
+            }
+            _ => State::Finished(Ok(())),
+        }
+    }
 
-                    let data = std::fs::read(in_filename)?;
+    /// Whether the data read is dumped to stdout, so messages must not be.
+    pub fn dumps_to_stdout(&self) -> bool {
+        matches!(self, UserCommand::Read { out_filename, .. } if out_filename == Path::new("-"))
+    }
 
-                    port.write_u8(0x01)?;
-                    port.write_u8(verify.into())?;
+    /// File to write a report of the mismatches found while verifying to.
+    fn mismatch_report(&self) -> Option<&Path> {
+        match self {
+            UserCommand::Verify {
+                report: Some(report),
+                ..
+            } => Some(report),
+            _ => None,
+        }
+    }
 
-                    State::Writing {
-                        current_byte: 0,
-                        data,
-                        verify,
-                    }
-                }
-                UserCommand::Verify {
-                    ref in_filename,
-                    fix,
-                } => {
-                    let data = std::fs::read(in_filename)?;
+    /// How many mismatches to collect before verification is cut short.
+    fn mismatch_limit(&self) -> Option<usize> {
+        match self {
+            UserCommand::Verify {
+                collect_mismatches, ..
+            } => collect_mismatches.filter(|&limit| limit > 0),
+            _ => None,
+        }
+    }
+}
 
-                    effects.push(Effect::PrintLn(
-                        "Initiating EEPROM verification...".to_owned(),
-                    ));
+fn default_end() -> u16 {
+    0x8000
+}
 
-                    port.write_u8(0x02)?;
-                    port.write_u8(fix.into())?;
+fn default_fill_value() -> u8 {
+    0xFF
+}
 
-                    State::Verifying {
-                        current_byte: 0,
-                        data,
-                        mismatches: vec![],
-                        fix,
-                    }
-                }
-            },
-            (state, Packet::Print(s)) => {
-                effects.push(Effect::Print(s));
-                state
-            }
-            (_, Packet::InvalidChecksum { expected, computed }) => {
-                State::Finished(Err(Error::ChecksumMismatch { expected, computed }))
-            }
+fn default_record_size() -> u8 {
+    16
+}
 
-            (
-                State::Reading {
-                    progress,
-                    total,
-                    mut out_file,
-                    out_path,
-                },
-                Packet::Chunk {
-                    data: chunk_data,
-                    checksum,
-                },
-            ) => {
-                let computed_checksum = protocol::calculate_checksum(&chunk_data);
+fn default_symbol_name() -> String {
+    "eeprom_data".to_owned()
+}
 
-                if checksum != computed_checksum {
-                    State::Finished(Err(Error::ChecksumMismatch {
-                        expected: checksum,
-                        computed: computed_checksum,
-                    }))
-                } else {
-                    let new_progress = progress + chunk_data.len();
-                    out_file.write_all(&chunk_data)?;
+fn default_report_limit() -> usize {
+    16
+}
 
-                    port.write_u8(0xFF)?;
+fn default_chunk_size() -> u8 {
+    32
+}
 
-                    effects.push(Effect::Progress {
-                        done: new_progress,
-                        total,
-                    });
+fn default_true() -> bool {
+    true
+}
 
-                    State::Reading {
-                        progress: new_progress,
-                        total,
-                        out_file,
-                        out_path,
-                    }
-                }
-            }
-            (State::Reading { out_path, .. }, Packet::ReadEnd) => {
-                effects.push(Effect::ProgressEnd);
-                effects.push(Effect::PrintLn(format!(
-                    "Memory contents successfully dumped to {:?}",
-                    out_path
-                )));
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct UserOptions {
+    pub command: UserCommand,
+    pub port: String,
+    pub hex_addresses: bool,
+    pub address_shift: u16,
+    pub batch_mismatches: bool,
+    pub max_address: Option<u16>,
+    pub timeout_recovery_reads: usize,
+    /// Consecutive Ready packets the board must send before it is trusted to
+    /// be done resetting.
+    pub wait_for_ready_count: u32,
+    pub line_ending: LineEndingMode,
+    /// Bytes sent to the board per chunk when writing or verifying.
+    pub chunk_size: u8,
+    /// How many corrupted chunks are re-sent before giving up.
+    pub max_retries: u8,
+    /// EEPROM model that operations must fit in.
+    pub chip: Chip,
+    /// Granularity that operations are aligned to. 1 means no alignment.
+    pub block_size: u16,
+    /// Firmware versions to refuse to run any operation on if not matched.
+    pub required_version: Option<VersionReq>,
+    /// Mismatches reported while verifying, as if the board had found them.
+    #[serde(skip)]
+    pub injected_mismatches: Vec<ByteMismatch>,
+    #[serde(
+        rename = "operation_timeout_ms",
+        deserialize_with = "deserialize_millis"
+    )]
+    pub operation_timeout: Duration,
+    /// How long the board may take to send the first chunk of a read.
+    #[serde(rename = "read_timeout_ms", deserialize_with = "deserialize_millis")]
+    pub read_timeout: Duration,
+    /// How long the board may take to ask for the first chunk of a write.
+    #[serde(rename = "write_timeout_ms", deserialize_with = "deserialize_millis")]
+    pub write_timeout: Duration,
+    /// How long the board may take to ask for the first chunk to verify.
+    #[serde(rename = "verify_timeout_ms", deserialize_with = "deserialize_millis")]
+    pub verify_timeout: Duration,
+    /// How long the board may take to erase the whole chip.
+    #[serde(rename = "erase_timeout_secs", deserialize_with = "deserialize_secs")]
+    pub erase_timeout: Duration,
+}
 
-                State::Finished(Ok(()))
-            }
+fn deserialize_secs<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    u64::deserialize(deserializer).map(Duration::from_secs)
+}
 
-            (
-                State::Writing {
-                    current_byte,
-                    data,
-                    verify,
-                },
-                Packet::ChunkRequest,
-            ) if current_byte >= data.len() => {
-                effects.push(Effect::ProgressEnd);
-                effects.push(Effect::PrintLn(format!(
-                    "{} bytes successfully written to EEPROM.",
-                    data.len()
-                )));
+fn deserialize_millis<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    u64::deserialize(deserializer).map(Duration::from_millis)
+}
 
-                port.write_u8(0x00)?;
+impl Default for UserOptions {
+    fn default() -> Self {
+        Self {
+            command: UserCommand::Read {
+                out_filename: PathBuf::from("dump.bin"),
+                start: 0x0000,
+                end: default_end(),
+                chunk_size: default_chunk_size(),
+                write_back: None,
+                format: FileFormat::Binary,
+                record_size: default_record_size(),
+                symbol_name: default_symbol_name(),
+                prepend_bom: false,
+                srec_record_type: SrecRecordType::S1,
+            },
+            port: serial::DEFAULT_PORT.to_owned(),
+            hex_addresses: false,
+            address_shift: 0,
+            batch_mismatches: false,
+            max_address: None,
+            timeout_recovery_reads: 0,
+            wait_for_ready_count: 1,
+            line_ending: LineEndingMode::Native,
+            chunk_size: DEFAULT_WRITE_CHUNK_SIZE,
+            max_retries: 3,
+            chip: Chip::default(),
+            block_size: 1,
+            required_version: None,
+            injected_mismatches: vec![],
+            operation_timeout: Duration::from_millis(500),
+            read_timeout: Duration::from_millis(DEFAULT_START_TIMEOUT_MS),
+            write_timeout: Duration::from_millis(DEFAULT_START_TIMEOUT_MS),
+            verify_timeout: Duration::from_millis(DEFAULT_START_TIMEOUT_MS),
+            erase_timeout: Duration::from_secs(30),
+        }
+    }
+}
 
-                if verify {
-                    effects.push(Effect::PrintLn("Verifying...".to_owned()));
+impl UserOptions {
+    pub fn with_command(self, command: UserCommand) -> Self {
+        Self { command, ..self }
+    }
 
-                    State::Verifying {
-                        data,
-                        current_byte: 0,
-                        mismatches: vec![],
-                        fix: true,
-                    }
-                } else {
-                    State::Finished(Ok(()))
-                }
-            }
-            (
-                State::Writing {
-                    mut current_byte,
-                    data,
-                    verify,
-                },
-                Packet::ChunkRequest,
-            ) => {
-                protocol::send_data_chunk(port, &data, &mut current_byte)?;
+    pub fn with_port(self, port: String) -> Self {
+        Self { port, ..self }
+    }
 
-                effects.push(Effect::Progress {
-                    done: current_byte,
-                    total: data.len(),
-                });
+    pub fn with_hex_addresses(self, hex_addresses: bool) -> Self {
+        Self {
+            hex_addresses,
+            ..self
+        }
+    }
 
-                State::Writing {
-                    current_byte,
-                    data,
-                    verify,
-                }
-            }
+    pub fn with_address_shift(self, address_shift: u16) -> Self {
+        Self {
+            address_shift,
+            ..self
+        }
+    }
 
-            (
-                State::Verifying {
-                    data,
-                    current_byte,
-                    mut mismatches,
-                    fix,
-                },
-                Packet::ByteMismatch {
-                    address, expected, ..
-                },
-            ) => {
-                effects.push(Effect::VerifyProgress {
-                    done: current_byte,
-                    total: data.len(),
-                    mismatches: mismatches.len(),
-                });
+    pub fn with_batch_mismatches(self, batch_mismatches: bool) -> Self {
+        Self {
+            batch_mismatches,
+            ..self
+        }
+    }
 
-                mismatches.push(ByteMismatch { address, expected });
+    pub fn with_max_address(self, max_address: Option<u16>) -> Self {
+        Self {
+            max_address,
+            ..self
+        }
+    }
 
-                State::Verifying {
-                    data,
-                    current_byte,
-                    mismatches,
-                    fix,
-                }
+    pub fn with_wait_for_ready_count(self, wait_for_ready_count: u32) -> Self {
+        Self {
+            wait_for_ready_count,
+            ..self
+        }
+    }
+
+    pub fn with_timeout_recovery_reads(self, timeout_recovery_reads: usize) -> Self {
+        Self {
+            timeout_recovery_reads,
+            ..self
+        }
+    }
+
+    pub fn with_line_ending(self, line_ending: LineEndingMode) -> Self {
+        Self {
+            line_ending,
+            ..self
+        }
+    }
+
+    pub fn with_chunk_size(self, chunk_size: u8) -> Self {
+        Self { chunk_size, ..self }
+    }
+
+    pub fn with_max_retries(self, max_retries: u8) -> Self {
+        Self {
+            max_retries,
+            ..self
+        }
+    }
+
+    pub fn with_chip(self, chip: Chip) -> Self {
+        Self { chip, ..self }
+    }
+
+    pub fn with_required_version(self, required_version: Option<VersionReq>) -> Self {
+        Self {
+            required_version,
+            ..self
+        }
+    }
+
+    pub fn with_block_size(self, block_size: u16) -> Self {
+        Self { block_size, ..self }
+    }
+
+    pub fn with_injected_mismatches(self, injected_mismatches: Vec<ByteMismatch>) -> Self {
+        Self {
+            injected_mismatches,
+            ..self
+        }
+    }
+
+    pub fn with_operation_timeout(self, operation_timeout: Duration) -> Self {
+        Self {
+            operation_timeout,
+            ..self
+        }
+    }
+
+    pub fn with_read_timeout(self, read_timeout: Duration) -> Self {
+        Self {
+            read_timeout,
+            ..self
+        }
+    }
+
+    pub fn with_write_timeout(self, write_timeout: Duration) -> Self {
+        Self {
+            write_timeout,
+            ..self
+        }
+    }
+
+    pub fn with_verify_timeout(self, verify_timeout: Duration) -> Self {
+        Self {
+            verify_timeout,
+            ..self
+        }
+    }
+
+    pub fn with_erase_timeout(self, erase_timeout: Duration) -> Self {
+        Self {
+            erase_timeout,
+            ..self
+        }
+    }
+
+    fn block_len(&self) -> usize {
+        usize::from(self.block_size.max(1))
+    }
+
+    /// Length of the chunk written at EEPROM address `address`. Page writes
+    /// end their chunks at page boundaries, so that each fits in one cycle.
+    fn write_chunk_len(&self, address: usize) -> usize {
+        if self.command.page_write() {
+            let page_size = usize::from(self.chip.spec().page_size);
+            page_size - address % page_size
+        } else {
+            self.chunk_size.into()
+        }
+    }
+
+    /// Offsets of the chunks a `len` bytes long image is written in, in the
+    /// order they should be written.
+    fn write_chunk_offsets(&self, len: usize) -> VecDeque<usize> {
+        let base = self.command.base_address();
+        let mut offsets = vec![];
+        let mut offset = 0;
+
+        while offset < len {
+            offsets.push(offset);
+            offset += self.write_chunk_len(base + offset);
+        }
+
+        self.command.write_order().order(offsets)
+    }
+
+    /// Builds the bytes a write puts on the chip from its input files, starting
+    /// at the write's start address. Returns `None` for other commands.
+    pub fn write_image(&self, emit: &mut dyn FnMut(Effect)) -> Result<Option<Vec<u8>>, Error> {
+        let UserCommand::Write {
+            ref in_filename,
+            skip,
+            trim,
+            format,
+            offset,
+            ref extra_files,
+            start,
+            end,
+            ..
+        } = self.command
+        else {
+            return Ok(None);
+        };
+
+        let mut data = format.load(in_filename)?;
+        data.drain(..skip.min(data.len()));
+
+        if let Some(trim) = trim {
+            let trimmed = data.len().saturating_sub(trim);
+            data.truncate(trim);
+
+            if trimmed != 0 {
+                emit(Effect::PrintLn(format!(
+                    "Warning: trimmed {} bytes from the end of the input file.",
+                    trimmed
+                )));
+            }
+        }
+
+        if offset != 0 {
+            data.splice(0..0, iter::repeat_n(0xFF, offset.into()));
+        }
+
+        for extra in extra_files {
+            let segment = format.load(&extra.path)?;
+            let segment_start = usize::from(extra.offset);
+            let segment_end = segment_start + segment.len();
+
+            emit(Effect::PrintLn(format!(
+                "Placing {:?} at {}.",
+                extra.path,
+                self.display_addr(extra.offset)
+            )));
+
+            if data.len() < segment_end {
+                data.resize(segment_end, 0xFF);
+            }
+
+            data[segment_start..segment_end].copy_from_slice(&segment);
+        }
+
+        if let Some(end) = end {
+            data.truncate(end.into());
+        }
+
+        data.drain(..usize::from(start));
+
+        let padded_len = data.len().next_multiple_of(self.block_len());
+
+        if padded_len != data.len() {
+            emit(Effect::PrintLn(format!(
+                "Padding input with {} bytes of 0xFF to fill the last block.",
+                padded_len - data.len()
+            )));
+            data.resize(padded_len, 0xFF);
+        }
+
+        Ok(Some(data))
+    }
+
+    /// Formats an EEPROM address as it should be shown to the user.
+    pub fn display_addr(&self, addr: u16) -> String {
+        format_addr(
+            u32::from(addr) + u32::from(self.address_shift),
+            self.hex_addresses,
+        )
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if !(1..=protocol::CHUNK_MAX_SIZE).contains(&self.chunk_size) {
+            return Err(Error::InvalidChunkSize {
+                chunk_size: self.chunk_size,
+                max: protocol::CHUNK_MAX_SIZE,
+            });
+        }
+
+        let capacity = self.chip.spec().capacity_bytes as usize;
+
+        // One past the highest address the operation touches
+        let end = match self.command {
+            UserCommand::Read {
+                start,
+                end,
+                ref symbol_name,
+                format,
+                srec_record_type,
+                ..
+            } => {
+                if end <= start {
+                    return Err(Error::InvalidRegionBounds { start, end });
+                }
+
+                if !c_header::is_valid_symbol(symbol_name) {
+                    return Err(Error::InvalidSymbolName {
+                        name: symbol_name.clone(),
+                    });
+                }
+
+                // The record holding the last byte has the highest address
+                let last_address = u32::from(end - 1);
+
+                if format == FileFormat::Srec && last_address > srec_record_type.max_address() {
+                    return Err(Error::AddressTooWideForSrec {
+                        address: last_address,
+                        record_type: srec_record_type,
+                    });
+                }
+
+                for address in [start, end] {
+                    if usize::from(address) % self.block_len() != 0 {
+                        return Err(Error::UnalignedBlockAccess {
+                            address,
+                            block_size: self.block_size,
+                        });
+                    }
+                }
+
+                usize::from(end)
+            }
+            UserCommand::Write {
+                ref in_filename,
+                skip,
+                trim,
+                ref checksum,
+                format,
+                offset,
+                ref extra_files,
+                start,
+                end,
+                ..
+            } => {
+                let file_size = validate_input_file(in_filename, format, skip, trim)?;
+
+                if file_size > capacity {
+                    return Err(Error::FileTooLarge {
+                        size: file_size,
+                        capacity,
+                    });
+                }
+
+                if let Some(checksum) = checksum {
+                    validate_checksum(in_filename, checksum)?;
+                }
+
+                let mut segments = vec![(offset, file_size, in_filename)];
+
+                for extra in extra_files {
+                    let size = validate_input_file(&extra.path, format, 0, None)?;
+                    segments.push((extra.offset, size, &extra.path));
+                }
+
+                for &(offset, file_size, _) in &segments {
+                    if usize::from(offset) + file_size > ADDRESS_SPACE {
+                        return Err(Error::OffsetExceedsAddressSpace {
+                            offset,
+                            file_size,
+                            address_space: ADDRESS_SPACE,
+                        });
+                    }
+                }
+
+                segments.sort_by_key(|&(offset, ..)| offset);
+
+                for pair in segments.windows(2) {
+                    let [(first, first_size, first_path), (second, _, second_path)] = pair else {
+                        unreachable!();
+                    };
+
+                    if usize::from(*second) < usize::from(*first) + first_size {
+                        return Err(Error::OverlappingInputs {
+                            first: first_path.to_path_buf(),
+                            second: second_path.to_path_buf(),
+                            address: *second,
+                        });
+                    }
+                }
+
+                let image_size = segments
+                    .iter()
+                    .map(|&(offset, file_size, _)| usize::from(offset) + file_size)
+                    .max()
+                    .unwrap();
+
+                let end = match end {
+                    Some(end) if usize::from(end) > image_size => {
+                        return Err(Error::RegionExceedsInput { end, image_size });
+                    }
+                    Some(end) => usize::from(end),
+                    None => image_size,
+                };
+
+                if usize::from(start) >= end {
+                    return Err(Error::InvalidRegionBounds {
+                        start,
+                        end: end.try_into().unwrap(),
+                    });
+                }
+
+                end.next_multiple_of(self.block_len())
+            }
+            UserCommand::Verify {
+                ref in_filename, ..
+            } => {
+                let file_size = validate_input_file(in_filename, FileFormat::Binary, 0, None)?;
+
+                if file_size > capacity {
+                    return Err(Error::FileTooLarge {
+                        size: file_size,
+                        capacity,
+                    });
+                }
+
+                file_size
+            }
+            UserCommand::BlankCheck { start, end, .. } => {
+                if end <= start {
+                    return Err(Error::InvalidRegionBounds { start, end });
+                }
+
+                usize::from(end)
+            }
+            UserCommand::Fill { start, end, .. } => {
+                if end <= start {
+                    return Err(Error::InvalidRegionBounds { start, end });
+                }
+
+                usize::from(end)
+            }
+            UserCommand::CheckFirmware | UserCommand::FirmwareVersion => 0,
+        };
+
+        if let Some(limit) = self.max_address
+            && end > usize::from(limit) + 1
+        {
+            return Err(Error::AddressExceedsLimit {
+                address: end - 1,
+                limit,
+            });
+        }
+
+        if self.command.page_write() && self.chip.spec().page_size == 1 {
+            return Err(Error::PageWriteUnsupported {
+                chip: self.chip.name(),
+            });
+        }
+
+        if end > capacity {
+            return Err(Error::ExceedsChipCapacity {
+                end,
+                chip: self.chip.name(),
+                capacity,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// How line endings in strings printed by the board are normalized.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LineEndingMode {
+    Crlf,
+    Lf,
+    #[default]
+    Native,
+}
+
+impl FromStr for LineEndingMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "crlf" => Ok(Self::Crlf),
+            "lf" => Ok(Self::Lf),
+            "native" => Ok(Self::Native),
+            _ => Err(format!(
+                "unknown line ending '{}' (expected crlf, lf or native)",
+                s
+            )),
+        }
+    }
+}
+
+/// Where a read dumps its data to. Dumping to `-` writes to stdout.
+#[derive(Debug)]
+pub enum DumpFile {
+    File(File),
+    Stdout(std::io::Stdout),
+}
+
+impl DumpFile {
+    fn create(path: &Path) -> std::io::Result<Self> {
+        if path == Path::new("-") {
+            Ok(Self::Stdout(std::io::stdout()))
+        } else {
+            File::create(path).map(Self::File)
+        }
+    }
+}
+
+impl Write for DumpFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::File(file) => file.write(buf),
+            Self::Stdout(stdout) => stdout.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::File(file) => file.flush(),
+            Self::Stdout(stdout) => stdout.flush(),
+        }
+    }
+}
+
+/// Byte order mark some Windows tools need to detect a text file as UTF-8.
+const UTF8_BOM: [u8; 3] = [0xEF, 0xBB, 0xBF];
+
+/// Format of the files data is read from or dumped to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileFormat {
+    #[default]
+    Binary,
+    /// Intel HEX.
+    Hex,
+    /// Motorola S-records.
+    Srec,
+    /// A C header declaring the data as an array. Output only.
+    CHeader,
+    /// A `hexdump -C` style listing. Output only.
+    Hexdump,
+}
+
+impl FileFormat {
+    /// Reads the data in the file at `path` as a flat image.
+    pub fn load(self, path: &Path) -> Result<Vec<u8>, Error> {
+        match self {
+            Self::Binary => Ok(std::fs::read(path)?),
+            Self::Hex => intel_hex::parse(&std::fs::read_to_string(path)?),
+            Self::Srec => srec::parse(&std::fs::read_to_string(path)?),
+            Self::CHeader => Err(Error::CHeaderInput),
+            Self::Hexdump => Err(Error::HexdumpInput),
+        }
+    }
+
+    /// Starts a dump of `size` bytes, before any data is written. With
+    /// `prepend_bom`, text formats start with a UTF-8 byte order mark.
+    fn begin(
+        self,
+        out: &mut impl Write,
+        size: usize,
+        symbol: &str,
+        prepend_bom: bool,
+    ) -> std::io::Result<()> {
+        if prepend_bom && self != Self::Binary {
+            out.write_all(&UTF8_BOM)?;
+        }
+
+        match self {
+            Self::CHeader => c_header::write_start(out, size, symbol),
+            _ => Ok(()),
+        }
+    }
+
+    /// Appends `data`, which starts at `address` in the EEPROM, to a dump.
+    /// Text formats put up to `record_size` bytes in each record, and SREC
+    /// dumps use records of `record_type`.
+    fn write_chunk(
+        self,
+        out: &mut impl Write,
+        address: u16,
+        data: &[u8],
+        record_size: u8,
+        record_type: SrecRecordType,
+    ) -> std::io::Result<()> {
+        match self {
+            Self::Binary => out.write_all(data),
+            Self::Hex => intel_hex::write_data(out, address, data, record_size),
+            Self::Srec => srec::write_data(out, address, data, record_size, record_type),
+            Self::CHeader => c_header::write_data(out, address, data),
+            Self::Hexdump => display::write_hexdump(out, address, data),
+        }
+    }
+
+    /// Completes a dump once all of its data has been written.
+    fn finish(self, out: &mut impl Write, record_type: SrecRecordType) -> std::io::Result<()> {
+        match self {
+            Self::Binary | Self::Hexdump => Ok(()),
+            Self::Hex => intel_hex::write_end_of_file(out),
+            Self::Srec => srec::write_termination(out, record_type),
+            Self::CHeader => c_header::write_end(out),
+        }
+    }
+}
+
+impl FromStr for FileFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "binary" => Ok(Self::Binary),
+            "hex" => Ok(Self::Hex),
+            "srec" => Ok(Self::Srec),
+            "c-header" => Ok(Self::CHeader),
+            "hexdump" => Ok(Self::Hexdump),
+            _ => Err(format!(
+                "unknown file format '{}' (expected binary, hex, srec, c-header or hexdump)",
+                s
+            )),
+        }
+    }
+}
+
+/// Size of the addresses in the records of an SREC dump.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SrecRecordType {
+    /// 16-bit addresses, in S1 records ended by an S9 record.
+    #[default]
+    S1,
+    /// 24-bit addresses, in S2 records ended by an S8 record.
+    S2,
+    /// 32-bit addresses, in S3 records ended by an S7 record.
+    S3,
+}
+
+impl SrecRecordType {
+    /// Highest address a record of this type can hold.
+    pub fn max_address(self) -> u32 {
+        match self {
+            Self::S1 => 0xFFFF,
+            Self::S2 => 0xFF_FFFF,
+            Self::S3 => u32::MAX,
+        }
+    }
+}
+
+impl FromStr for SrecRecordType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "s1" => Ok(Self::S1),
+            "s2" => Ok(Self::S2),
+            "s3" => Ok(Self::S3),
+            _ => Err(format!(
+                "unknown SREC record type '{}' (expected s1, s2 or s3)",
+                s
+            )),
+        }
+    }
+}
+
+/// Order in which the chunks of a file are written to the EEPROM.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WriteOrder {
+    #[default]
+    Sequential,
+    Reverse,
+    /// Even-numbered chunks first, then odd-numbered ones.
+    Interleaved,
+}
+
+impl WriteOrder {
+    /// Puts the offsets of a file's chunks, given in ascending order, in the
+    /// order they should be written.
+    fn order(self, offsets: Vec<usize>) -> VecDeque<usize> {
+        match self {
+            Self::Sequential => offsets.into(),
+            Self::Reverse => offsets.into_iter().rev().collect(),
+            Self::Interleaved => {
+                let (even, odd): (Vec<_>, Vec<_>) = offsets
+                    .into_iter()
+                    .enumerate()
+                    .partition(|(i, _)| i % 2 == 0);
+
+                even.into_iter()
+                    .chain(odd)
+                    .map(|(_, offset)| offset)
+                    .collect()
+            }
+        }
+    }
+}
+
+impl FromStr for WriteOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sequential" => Ok(Self::Sequential),
+            "reverse" => Ok(Self::Reverse),
+            "interleaved" => Ok(Self::Interleaved),
+            _ => Err(format!(
+                "unknown write order '{}' (expected sequential, reverse or interleaved)",
+                s
+            )),
+        }
+    }
+}
+
+pub fn normalize_line_endings(s: &str, mode: LineEndingMode) -> Cow<'_, str> {
+    match mode {
+        LineEndingMode::Lf if s.contains('\r') => Cow::Owned(s.replace('\r', "")),
+        LineEndingMode::Crlf if s.contains('\n') => {
+            let mut out = String::with_capacity(s.len());
+            let mut prev = None;
+
+            for c in s.chars() {
+                if c == '\n' && prev != Some('\r') {
+                    out.push('\r');
+                }
+
+                out.push(c);
+                prev = Some(c);
+            }
+
+            Cow::Owned(out)
+        }
+        _ => Cow::Borrowed(s),
+    }
+}
+
+pub fn format_addr(addr: u32, hex: bool) -> String {
+    if hex {
+        format!("0x{:04X}", addr)
+    } else {
+        addr.to_string()
+    }
+}
+
+/// Checks that the file fits the chip, returning how many bytes will be used.
+fn validate_input_file(
+    path: &Path,
+    format: FileFormat,
+    skip: usize,
+    trim: Option<usize>,
+) -> Result<usize, Error> {
+    if !path.is_file() {
+        return Err(Error::FileNotFound(path.to_owned()));
+    }
+
+    let mut file_size = match format {
+        FileFormat::Binary => std::fs::metadata(path)?.len() as usize,
+        _ => format.load(path)?.len(),
+    };
+
+    if skip != 0 {
+        if skip >= file_size {
+            return Err(Error::SkipExceedsFileSize { skip, file_size });
+        }
+
+        file_size -= skip;
+    }
+
+    if let Some(trim) = trim {
+        if trim > file_size {
+            return Err(Error::TrimExceedsFileSize { trim, file_size });
+        }
+
+        file_size = trim;
+    }
+
+    if file_size > ADDRESS_SPACE {
+        return Err(Error::FileTooLargeForAddress {
+            file_size,
+            address_space: ADDRESS_SPACE,
+        });
+    }
+
+    Ok(file_size)
+}
+
+fn validate_checksum(path: &Path, checksum: &InputChecksum) -> Result<(), Error> {
+    let data = std::fs::read(path)?;
+
+    checksum
+        .check(&data)
+        .map_err(|computed| Error::InputChecksumMismatch {
+            expected: checksum.expected.clone(),
+            computed,
+            algorithm: checksum.algorithm.to_string(),
+        })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StateKind {
+    Idle,
+    Reading,
+    Writing,
+    Verifying,
+    Fixing,
+    AwaitingReadBack,
+    ReadingBack,
+    AwaitingWrite,
+    Erasing,
+    AwaitingSpotCheck,
+    SpotChecking,
+    BlankChecking,
+    CheckingFirmware,
+    Settling,
+    Negotiating,
+    Negotiated,
+    QueryingVersion,
+    VersionChecked,
+    QueryingLimits,
+    LimitsChecked,
+    Aborting,
+    Finished,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Effect {
+    Print(String),
+    PrintLn(String),
+    Progress {
+        done: usize,
+        total: usize,
+    },
+    VerifyProgress {
+        done: usize,
+        total: usize,
+        mismatches: usize,
+    },
+    WriteProgress {
+        written: usize,
+        total: usize,
+        chunk_size: usize,
+        current_chunk: usize,
+        total_chunks: usize,
+    },
+    FixingByte {
+        current: usize,
+        total: usize,
+        address: u16,
+        value: u8,
+    },
+    /// Still waiting for the board to finish erasing the EEPROM.
+    Erasing {
+        elapsed: Duration,
+    },
+    ProgressEnd,
+    Warning(String),
+    /// A non-fatal issue with the requested operation itself.
+    OperationWarning(String),
+    /// Something the user should notice right away, e.g. the chip changing
+    /// while it is being watched.
+    Alert(String),
+    MismatchReport {
+        mismatches: Vec<ByteMismatch>,
+    },
+    WriteBackChunk(Vec<u8>),
+    WriteBackEnd,
+    TransferStats {
+        bytes_transferred: usize,
+        mismatches: usize,
+    },
+    /// How a read, write or verify went, once its data has gone through.
+    TransferSummary {
+        operation: OperationKind,
+        bytes: usize,
+        duration_ms: u64,
+        /// Checksum of all the bytes transferred.
+        checksum: u16,
+        retries: u32,
+    },
+    /// What a write did, once it is done.
+    WriteSummary {
+        written: usize,
+        verified: usize,
+        fixed: usize,
+    },
+    /// What a dry run would have written, had it not been one.
+    DryRunSummary {
+        file_size: usize,
+        checksum: u16,
+    },
+}
+
+/// Transfer that a [`Effect::TransferSummary`] is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperationKind {
+    Read,
+    Write,
+    Verify,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ByteMismatch {
+    pub address: u16,
+    pub expected: u8,
+    pub found: u8,
+}
+
+/// A file written alongside the main input file, at its own address.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct InputSegment {
+    pub path: PathBuf,
+    pub offset: u16,
+}
+
+/// A byte that should hold a known value once a write is done.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct SpotCheck {
+    pub address: u16,
+    pub expected: u8,
+}
+
+/// Why the EEPROM contents are being read back into memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadBackPurpose {
+    /// Compare the contents against data that was just written.
+    Verify,
+    /// Make sure that writing the data would only touch blank cells.
+    WriteOnceCheck {
+        verify: bool,
+        verify_with_read: bool,
+    },
+}
+
+/// Tracks the last chunk sent to the board, so that it can be sent again if
+/// it arrives corrupted.
+#[derive(Debug)]
+pub struct ChunkRetry {
+    last_chunk: usize,
+    remaining: u8,
+}
+
+impl ChunkRetry {
+    fn new(max_retries: u8) -> Self {
+        Self {
+            last_chunk: 0,
+            remaining: max_retries,
+        }
+    }
+
+    /// How many chunks were re-sent, out of the `max_retries` allowed.
+    fn used(&self, max_retries: u8) -> u32 {
+        (max_retries - self.remaining).into()
+    }
+}
+
+fn elapsed_ms(since: Instant) -> u64 {
+    since.elapsed().as_millis().try_into().unwrap_or(u64::MAX)
+}
+
+/// Chunks of a read that timed out and were filled with placeholder bytes.
+#[derive(Debug, Default)]
+pub struct ReadRecovery {
+    consecutive_skips: usize,
+    recovered: Vec<Range<usize>>,
+}
+
+#[derive(Debug)]
+pub enum State {
+    Idle,
+    Reading {
+        start: u16,
+        chunk_size: usize,
+        progress: usize,
+        total: usize,
+        recovery: ReadRecovery,
+        out_file: DumpFile,
+        out_path: PathBuf,
+        write_back: bool,
+        started_at: Instant,
+        /// Checksum of the bytes read so far.
+        checksum: RunningChecksum,
+        /// When the board must have answered the command that started the
+        /// operation by. Cleared once it does.
+        deadline: Option<Instant>,
+    },
+    Writing {
+        current_byte: usize,
+        /// Offsets of the chunks left to write.
+        queue: VecDeque<usize>,
+        /// Where the board will write the next chunk it receives.
+        board_address: usize,
+        pages_skipped: usize,
+        data: Vec<u8>,
+        verify: bool,
+        verify_with_read: bool,
+        retry: ChunkRetry,
+        started_at: Instant,
+        deadline: Option<Instant>,
+    },
+    Verifying {
+        current_byte: usize,
+        data: Vec<u8>,
+        mismatches: Vec<ByteMismatch>,
+        fix: bool,
+        retry: ChunkRetry,
+        started_at: Instant,
+        deadline: Option<Instant>,
+    },
+    Fixing {
+        mismatches: Vec<ByteMismatch>,
+        current: usize,
+        /// Bytes that were verified before fixing.
+        verified: usize,
+    },
+    AwaitingReadBack {
+        data: Vec<u8>,
+        purpose: ReadBackPurpose,
+    },
+    ReadingBack {
+        data: Vec<u8>,
+        buffer: Cursor<Vec<u8>>,
+        purpose: ReadBackPurpose,
+    },
+    AwaitingWrite {
+        data: Vec<u8>,
+        verify: bool,
+        verify_with_read: bool,
+    },
+    Erasing {
+        started_at: Instant,
+        data: Vec<u8>,
+        verify: bool,
+        verify_with_read: bool,
+    },
+    AwaitingSpotCheck {
+        checks: VecDeque<SpotCheck>,
+        failed: usize,
+    },
+    SpotChecking {
+        check: SpotCheck,
+        found: Option<u8>,
+        checks: VecDeque<SpotCheck>,
+        failed: usize,
+    },
+    BlankChecking {
+        start: u16,
+        progress: usize,
+        total: usize,
+        /// The first few addresses that were not blank.
+        non_blank: Vec<u16>,
+        non_blank_count: usize,
+    },
+    CheckingFirmware,
+    /// Waiting for more consecutive Ready packets before trusting that the
+    /// board is done resetting. Each one is answered with a no-op command,
+    /// which makes the board send the next.
+    Settling {
+        readies: u32,
+    },
+    /// Agreeing on the protocol version with the board. The version itself is
+    /// only sent once the board acknowledges the request, so that older
+    /// firmware never mistakes it for a command.
+    Negotiating {
+        version_sent: bool,
+    },
+    /// Both sides speak the same protocol, so the operation can start on the
+    /// next Ready.
+    Negotiated,
+    /// Waiting for the board to report its firmware version.
+    QueryingVersion,
+    /// The firmware version is fine, so the operation starts on the next
+    /// Ready.
+    VersionChecked,
+    /// Waiting for the board to report the largest chunk and page it takes.
+    QueryingLimits,
+    /// The firmware can handle the chip, so the probe write is sent on the
+    /// next Ready.
+    LimitsChecked,
+    /// Waiting for the board to acknowledge that it stopped writing or
+    /// verifying.
+    Aborting {
+        done: usize,
+        total: usize,
+        action: &'static str,
+    },
+    Finished(Result<(), Error>),
+}
+
+impl State {
+    pub fn kind(&self) -> StateKind {
+        match self {
+            Self::Idle => StateKind::Idle,
+            Self::Reading { .. } => StateKind::Reading,
+            Self::Writing { .. } => StateKind::Writing,
+            Self::Verifying { .. } => StateKind::Verifying,
+            Self::Fixing { .. } => StateKind::Fixing,
+            Self::AwaitingReadBack { .. } => StateKind::AwaitingReadBack,
+            Self::ReadingBack { .. } => StateKind::ReadingBack,
+            Self::AwaitingWrite { .. } => StateKind::AwaitingWrite,
+            Self::Erasing { .. } => StateKind::Erasing,
+            Self::AwaitingSpotCheck { .. } => StateKind::AwaitingSpotCheck,
+            Self::SpotChecking { .. } => StateKind::SpotChecking,
+            Self::BlankChecking { .. } => StateKind::BlankChecking,
+            Self::CheckingFirmware => StateKind::CheckingFirmware,
+            Self::Settling { .. } => StateKind::Settling,
+            Self::Negotiating { .. } => StateKind::Negotiating,
+            Self::Negotiated => StateKind::Negotiated,
+            Self::QueryingVersion => StateKind::QueryingVersion,
+            Self::VersionChecked => StateKind::VersionChecked,
+            Self::QueryingLimits => StateKind::QueryingLimits,
+            Self::LimitsChecked => StateKind::LimitsChecked,
+            Self::Aborting { .. } => StateKind::Aborting,
+            Self::Finished(_) => StateKind::Finished,
+        }
+    }
+
+    #[tracing::instrument(
+        skip_all,
+        fields(state_kind = ?self.kind(), %packet, next_state_kind = tracing::field::Empty)
+    )]
+    pub fn transition(
+        self,
+        packet: Packet,
+        port: &mut impl SerialIO,
+        opts: &UserOptions,
+        emit: &mut dyn FnMut(Effect),
+    ) -> Result<State, Error> {
+        // The board is up, so from now on it should answer promptly
+        if let Packet::Ready = packet {
+            port.set_timeout(opts.operation_timeout)?;
+        }
+
+        let next_state = match (self, packet) {
+            // The board only fixes the mismatches it found itself, so it skips
+            // fixing when all of them were injected
+            (State::Fixing { .. }, Packet::Ready) if !opts.injected_mismatches.is_empty() => {
+                emit(Effect::ProgressEnd);
+                emit(Effect::PrintLn(
+                    "Board found nothing to fix besides the injected mismatches.".to_owned(),
+                ));
+
+                State::Finished(Ok(()))
+            }
+            (State::Idle, Packet::Ready) if opts.wait_for_ready_count > 1 => {
+                protocol::send_command(port, protocol::NOP)?;
+                State::Settling { readies: 1 }
+            }
+            (State::Settling { readies }, Packet::Ready)
+                if readies + 1 < opts.wait_for_ready_count =>
+            {
+                protocol::send_command(port, protocol::NOP)?;
+                State::Settling {
+                    readies: readies + 1,
+                }
+            }
+            (State::Idle | State::Settling { .. }, Packet::Ready) => {
+                port.set_timeout(NEGOTIATION_TIMEOUT)?;
+                protocol::send_command(port, protocol::NEGOTIATE)?;
+
+                State::Negotiating {
+                    version_sent: false,
+                }
+            }
+            (
+                State::Negotiating {
+                    version_sent: false,
+                },
+                Packet::Ack,
+            ) => {
+                port.write_u16(protocol::PROTOCOL_VERSION)?;
+
+                State::Negotiating { version_sent: true }
+            }
+            (State::Negotiating { version_sent: true }, Packet::VersionOk) => {
+                port.set_timeout(opts.operation_timeout)?;
+                State::Negotiated
+            }
+            (
+                State::Negotiating { version_sent: true },
+                Packet::VersionMismatch { firmware_version },
+            ) => State::Finished(Err(Error::ProtocolVersionMismatch {
+                host: protocol::PROTOCOL_VERSION,
+                firmware: firmware_version,
+            })),
+            // Firmware from before negotiation ignores it and asks for a command
+            // again. It still expects the protocol 1 erase command, so carrying
+            // on with it is not safe.
+            (
+                State::Negotiating {
+                    version_sent: false,
+                },
+                packet @ Packet::Ready,
+            ) => State::Finished(Err(Error::IncompatibleFirmware { packet })),
+            (State::Negotiated, Packet::Ready) => Self::begin(port, opts, emit)?,
+            // Older firmware ignores the query and asks for a command again
+            (State::QueryingVersion, Packet::Ready) => {
+                State::Finished(Err(Error::UnknownFirmwareVersion))
+            }
+            (State::AwaitingReadBack { data, purpose }, Packet::Ready) => {
+                Self::start_read_back(port, data, purpose, opts)?
+            }
+            (
+                State::AwaitingWrite {
+                    data,
+                    verify,
+                    verify_with_read,
+                },
+                Packet::Ready,
+            ) => Self::start_write(port, data, verify, verify_with_read, opts)?,
+            (State::AwaitingSpotCheck { mut checks, failed }, Packet::Ready) => {
+                let check = checks.pop_front().unwrap();
+
+                protocol::send_command(port, 0x00)?;
+                port.write_u16(check.address)?;
+                port.write_u16(check.address.saturating_add(1))?;
+                port.write_u8(1)?;
+
+                State::SpotChecking {
+                    check,
+                    found: None,
+                    checks,
+                    failed,
+                }
+            }
+            (State::VersionChecked, Packet::Ready) => Self::start_command(port, opts, emit)?,
+            (state, Packet::Print(s)) => {
+                emit(Effect::Print(
+                    normalize_line_endings(&s, opts.line_ending).into_owned(),
+                ));
+                state
+            }
+            // Leftovers from before the board reset, which also break a run of
+            // Ready packets
+            (State::Idle | State::Settling { .. }, _) => State::Idle,
+            (state @ State::Aborting { .. }, Packet::ChunkRequest) => {
+                protocol::send_abort(port)?;
+                state
+            }
+            (
+                State::Aborting {
+                    done,
+                    total,
+                    action,
+                },
+                Packet::Ack,
+            ) => {
+                emit(Effect::PrintLn(format!(
+                    "Aborted after {}/{} bytes {}.",
+                    done, total, action
+                )));
+
+                State::Finished(Err(Error::Interrupted))
+            }
+            // Whatever the board reports about the last chunk no longer matters
+            (
+                state @ State::Aborting { .. },
+                Packet::InvalidChecksum { .. } | Packet::ByteMismatch { .. },
+            ) => state,
+
+            (
+                State::Writing {
+                    mut current_byte,
+                    mut queue,
+                    board_address,
+                    pages_skipped,
+                    data,
+                    verify,
+                    verify_with_read,
+                    mut retry,
+                    started_at,
+                    ..
+                },
+                Packet::InvalidChecksum { .. },
+            ) if retry.remaining > 0 => {
+                retry.remaining -= 1;
+                emit(Effect::Warning(format!(
+                    "Chunk at {} arrived corrupted, retrying ({} retries left)",
+                    opts.display_addr(retry.last_chunk.try_into().unwrap()),
+                    retry.remaining
+                )));
+
+                // The board drops corrupted chunks, so it is still at their start
+                let chunk_address = opts.command.base_address() + retry.last_chunk;
+                current_byte -= board_address - chunk_address;
+                queue.push_front(retry.last_chunk);
+
+                State::Writing {
+                    current_byte,
+                    queue,
+                    board_address: chunk_address,
+                    pages_skipped,
+                    data,
+                    verify,
+                    verify_with_read,
+                    retry,
+                    started_at,
+                    deadline: None,
+                }
+            }
+            (
+                State::Verifying {
+                    data,
+                    mut mismatches,
+                    fix,
+                    mut retry,
+                    started_at,
+                    ..
+                },
+                Packet::InvalidChecksum { .. },
+            ) if retry.remaining > 0 => {
+                retry.remaining -= 1;
+                emit(Effect::Warning(format!(
+                    "Chunk at {} arrived corrupted, retrying ({} retries left)",
+                    opts.display_addr(retry.last_chunk.try_into().unwrap()),
+                    retry.remaining
+                )));
+
+                // Injected mismatches are added again when the chunk is re-sent.
+                // Mismatch addresses are absolute, while chunks are offsets into
+                // the data.
+                let chunk_address = opts.command.base_address() + retry.last_chunk;
+                mismatches.retain(|m| usize::from(m.address) < chunk_address);
+
+                State::Verifying {
+                    current_byte: retry.last_chunk,
+                    data,
+                    mismatches,
+                    fix,
+                    retry,
+                    started_at,
+                    deadline: None,
+                }
+            }
+            (_, Packet::InvalidChecksum { expected, computed }) => {
+                // The board keeps asking for chunks, so end its loop
+                port.write_u8(0x00)?;
+
+                State::Finished(Err(Error::ChecksumMismatch { expected, computed }))
+            }
+            (_, Packet::Error { code, message }) => {
+                State::Finished(Err(Error::BoardError { code, message }))
+            }
+
+            (
+                State::Reading {
+                    start,
+                    chunk_size,
+                    progress,
+                    total,
+                    recovery,
+                    mut out_file,
+                    out_path,
+                    write_back,
+                    started_at,
+                    checksum: mut running_checksum,
+                    ..
+                },
+                Packet::Chunk {
+                    data: chunk_data,
+                    checksum,
+                },
+            ) => {
+                let computed_checksum = protocol::calculate_checksum(&chunk_data);
+
+                if checksum != computed_checksum {
+                    State::Finished(Err(Error::ChecksumMismatch {
+                        expected: checksum,
+                        computed: computed_checksum,
+                    }))
+                } else {
+                    let new_progress = progress + chunk_data.len();
+                    running_checksum.update(&chunk_data);
+                    opts.command.file_format().write_chunk(
+                        &mut out_file,
+                        start + progress as u16,
+                        &chunk_data,
+                        opts.command.record_size(),
+                        opts.command.srec_record_type(),
+                    )?;
+
+                    port.write_u8(0xFF)?;
+
+                    emit(Effect::Progress {
+                        done: new_progress,
+                        total,
+                    });
+
+                    if write_back {
+                        emit(Effect::WriteBackChunk(chunk_data));
+                    }
+
+                    State::Reading {
+                        start,
+                        chunk_size,
+                        progress: new_progress,
+                        total,
+                        recovery: ReadRecovery {
+                            consecutive_skips: 0,
+                            ..recovery
+                        },
+                        out_file,
+                        out_path,
+                        write_back,
+                        started_at,
+                        checksum: running_checksum,
+                        deadline: None,
+                    }
+                }
+            }
+            (
+                State::Reading {
+                    start,
+                    progress,
+                    recovery,
+                    mut out_file,
+                    out_path,
+                    write_back,
+                    started_at,
+                    checksum,
+                    ..
+                },
+                Packet::ReadEnd,
+            ) => {
+                opts.command
+                    .file_format()
+                    .finish(&mut out_file, opts.command.srec_record_type())?;
+
+                emit(Effect::ProgressEnd);
+                emit(Effect::TransferStats {
+                    bytes_transferred: progress,
+                    mismatches: 0,
+                });
+                emit(Effect::TransferSummary {
+                    operation: OperationKind::Read,
+                    bytes: progress,
+                    duration_ms: elapsed_ms(started_at),
+                    checksum: checksum.value(),
+                    retries: 0,
+                });
+
+                if !recovery.recovered.is_empty() {
+                    emit(Effect::PrintLn(format!(
+                        "Filled {} timed out ranges with 0x{:02X}:",
+                        recovery.recovered.len(),
+                        RECOVERY_PLACEHOLDER
+                    )));
+
+                    for range in &recovery.recovered {
+                        emit(Effect::PrintLn(format!(
+                            "  {} to {}",
+                            opts.display_addr(start + range.start as u16),
+                            opts.display_addr(start + range.end as u16)
+                        )));
+                    }
+                }
+                if let DumpFile::Stdout(_) = out_file {
+                    emit(Effect::PrintLn(
+                        "Memory contents successfully dumped to stdout".to_owned(),
+                    ));
+                } else {
+                    emit(Effect::PrintLn(format!(
+                        "Memory contents successfully dumped to {:?}",
+                        out_path
+                    )));
+                }
+
+                if write_back {
+                    emit(Effect::WriteBackEnd);
+                    emit(Effect::PrintLn(
+                        "Memory contents successfully written back.".to_owned(),
+                    ));
+                }
+
+                State::Finished(Ok(()))
+            }
+
+            (
+                State::Writing {
+                    queue,
+                    pages_skipped,
+                    data,
+                    verify,
+                    verify_with_read,
+                    retry,
+                    started_at,
+                    ..
+                },
+                Packet::ChunkRequest,
+            ) if queue.is_empty() => {
+                emit(Effect::ProgressEnd);
+                emit(Effect::PrintLn(format!(
+                    "{} bytes successfully written to EEPROM.",
+                    format_addr(data.len().try_into().unwrap(), opts.hex_addresses)
+                )));
+
+                if let UserCommand::Write {
+                    only_data_pages: true,
+                    ..
+                } = opts.command
+                {
+                    let pages = opts.write_chunk_offsets(data.len()).len();
+
+                    emit(Effect::PrintLn(format!(
+                        "Skipped {} of {} pages (all 0xFF). Wrote {} pages.",
+                        pages_skipped,
+                        pages,
+                        pages - pages_skipped
+                    )));
+                }
+
+                emit(Effect::TransferStats {
+                    bytes_transferred: data.len(),
+                    mismatches: 0,
+                });
+                emit(Effect::TransferSummary {
+                    operation: OperationKind::Write,
+                    bytes: data.len(),
+                    duration_ms: elapsed_ms(started_at),
+                    checksum: protocol::calculate_checksum(&data),
+                    retries: retry.used(opts.max_retries),
+                });
+
+                port.write_u8(0x00)?;
+
+                if verify_with_read {
+                    emit(Effect::PrintLn(
+                        "Reading back EEPROM contents...".to_owned(),
+                    ));
+
+                    State::AwaitingReadBack {
+                        data,
+                        purpose: ReadBackPurpose::Verify,
+                    }
+                } else if verify {
+                    emit(Effect::PrintLn("Verifying...".to_owned()));
+
+                    State::Verifying {
+                        data,
+                        current_byte: 0,
+                        mismatches: vec![],
+                        fix: opts.command.fixes_after_write(),
+                        retry: ChunkRetry::new(opts.max_retries),
+                        started_at: Instant::now(),
+                        deadline: Some(Instant::now() + opts.verify_timeout),
+                    }
+                } else {
+                    opts.command.after_write(data.len(), 0, 0, emit)
+                }
+            }
+            (
+                State::Writing {
+                    mut current_byte,
+                    mut queue,
+                    mut board_address,
+                    mut pages_skipped,
+                    data,
+                    verify,
+                    verify_with_read,
+                    mut retry,
+                    started_at,
+                    ..
+                },
+                Packet::ChunkRequest,
+            ) => {
+                let only_data_pages = matches!(
+                    opts.command,
+                    UserCommand::Write {
+                        only_data_pages: true,
+                        ..
+                    }
+                );
+
+                let base = opts.command.base_address();
+                let offset = queue.pop_front().unwrap();
+                retry.last_chunk = offset;
+
+                if base + offset != board_address {
+                    protocol::send_set_address(port, (base + offset).try_into().unwrap())?;
+                }
+
+                let mut cursor = offset;
+                let chunk_size = opts.write_chunk_len(base + offset);
+
+                if protocol::send_data_chunk(port, &data, &mut cursor, chunk_size, only_data_pages)?
+                {
+                    pages_skipped += 1;
+                }
+
+                current_byte += cursor - offset;
+                board_address = base + cursor;
+
+                let total_chunks = opts.write_chunk_offsets(data.len()).len();
+
+                emit(Effect::WriteProgress {
+                    written: current_byte,
+                    total: data.len(),
+                    chunk_size,
+                    current_chunk: total_chunks - queue.len(),
+                    total_chunks,
+                });
+
+                State::Writing {
+                    current_byte,
+                    queue,
+                    board_address,
+                    pages_skipped,
+                    data,
+                    verify,
+                    verify_with_read,
+                    retry,
+                    started_at,
+                    deadline: None,
+                }
+            }
+
+            (
+                State::Verifying {
+                    data,
+                    current_byte,
+                    mut mismatches,
+                    fix,
+                    retry,
+                    started_at,
+                    ..
+                },
+                Packet::ByteMismatch {
+                    address,
+                    expected,
+                    found,
+                },
+            ) => {
+                if !opts.batch_mismatches {
+                    emit(Effect::VerifyProgress {
+                        done: current_byte,
+                        total: data.len(),
+                        mismatches: mismatches.len(),
+                    });
+                }
+
+                mismatches.push(ByteMismatch {
+                    address,
+                    expected,
+                    found,
+                });
+
+                State::Verifying {
+                    data,
+                    current_byte,
+                    mismatches,
+                    fix,
+                    retry,
+                    started_at,
+                    deadline: None,
+                }
+            }
+            (
+                State::Verifying {
+                    data,
+                    current_byte,
+                    mismatches,
+                    fix,
+                    retry,
+                    started_at,
+                    ..
+                },
+                Packet::ChunkRequest,
+            ) if current_byte >= data.len()
+                || opts
+                    .command
+                    .mismatch_limit()
+                    .is_some_and(|limit| mismatches.len() >= limit) =>
+            {
+                port.write_u8(0x00)?;
+
+                emit(Effect::ProgressEnd);
+                emit(Effect::TransferStats {
+                    bytes_transferred: current_byte,
+                    mismatches: mismatches.len(),
+                });
+                emit(Effect::TransferSummary {
+                    operation: OperationKind::Verify,
+                    bytes: current_byte.min(data.len()),
+                    duration_ms: elapsed_ms(started_at),
+                    checksum: protocol::calculate_checksum(&data[..current_byte.min(data.len())]),
+                    retries: retry.used(opts.max_retries),
+                });
+
+                if let Some(report) = opts.command.mismatch_report() {
+                    mismatch_report::write(
+                        report,
+                        &mismatches,
+                        &data,
+                        opts.command.base_address(),
+                    )?;
+
+                    emit(Effect::PrintLn(format!(
+                        "Wrote mismatch report to {:?}.",
+                        report
+                    )));
+                }
+
+                if mismatches.is_empty() {
+                    emit(Effect::PrintLn("No mismatches found.".to_owned()));
+                    opts.command.after_write(data.len(), current_byte, 0, emit)
+                } else {
+                    if current_byte < data.len() {
+                        emit(Effect::PrintLn(format!(
+                            "Verification aborted after {} mismatches (limit reached).",
+                            mismatches.len()
+                        )));
+                    } else {
+                        emit(Effect::PrintLn(format!(
+                            "{} mismatches found.",
+                            mismatches.len()
+                        )));
+                    }
+
+                    if opts.batch_mismatches {
+                        emit(Effect::MismatchReport {
+                            mismatches: mismatches.clone(),
+                        });
+                    }
+
+                    if fix {
+                        State::Fixing {
+                            mismatches,
+                            current: 0,
+                            verified: current_byte,
+                        }
+                    } else if let UserCommand::Write { .. } = opts.command {
+                        State::Finished(Err(Error::VerificationFailed {
+                            mismatches: mismatches.len(),
+                        }))
+                    } else {
+                        State::Finished(Ok(()))
+                    }
+                }
             }
             (
                 State::Verifying {
-                    data,
+                    mut current_byte,
+                    data,
+                    mut mismatches,
+                    fix,
+                    mut retry,
+                    started_at,
+                    ..
+                },
+                Packet::ChunkRequest,
+            ) => {
+                let base = opts.command.base_address();
+                let chunk_start = current_byte;
+                retry.last_chunk = chunk_start;
+
+                // The board starts verifying at address 0
+                if chunk_start == 0 && base != 0 {
+                    protocol::send_set_address(port, base.try_into().unwrap())?;
+                }
+
+                protocol::send_data_chunk(
+                    &mut *port,
+                    &data,
+                    &mut current_byte,
+                    opts.chunk_size.into(),
+                    false,
+                )?;
+
+                mismatches.extend(
+                    opts.injected_mismatches
+                        .iter()
+                        .filter(|m| {
+                            (base + chunk_start..base + current_byte)
+                                .contains(&usize::from(m.address))
+                        })
+                        .cloned(),
+                );
+
+                emit(Effect::VerifyProgress {
+                    done: current_byte,
+                    total: data.len(),
+                    mismatches: mismatches.len(),
+                });
+
+                State::Verifying {
                     current_byte,
+                    data,
                     mismatches,
                     fix,
+                    retry,
+                    started_at,
+                    deadline: None,
+                }
+            }
+
+            (
+                State::Fixing {
+                    mismatches,
+                    current,
+                    verified,
                 },
-                Packet::ChunkRequest,
-            ) if current_byte >= data.len() => {
+                Packet::ByteRequest,
+            ) if current >= mismatches.len() => {
+                port.write_u16(0xFFFF)?;
+
+                emit(Effect::ProgressEnd);
+                emit(Effect::PrintLn("Mismatches fixed successfully.".to_owned()));
+
+                opts.command
+                    .after_write(verified, verified, mismatches.len(), emit)
+            }
+            (
+                State::Fixing {
+                    mismatches,
+                    mut current,
+                    verified,
+                },
+                Packet::ByteRequest,
+            ) => {
+                let mismatch = &mismatches[current];
+                current += 1;
+
+                port.write_u16(mismatch.address)?;
+                port.write_u8(mismatch.expected)?;
+
+                emit(Effect::FixingByte {
+                    current,
+                    total: mismatches.len(),
+                    address: mismatch.address,
+                    value: mismatch.expected,
+                });
+                State::Fixing {
+                    mismatches,
+                    current,
+                    verified,
+                }
+            }
+
+            (
+                State::ReadingBack {
+                    data,
+                    mut buffer,
+                    purpose,
+                },
+                Packet::Chunk {
+                    data: chunk_data,
+                    checksum,
+                },
+            ) => {
+                let computed_checksum = protocol::calculate_checksum(&chunk_data);
+
+                if checksum != computed_checksum {
+                    State::Finished(Err(Error::ChecksumMismatch {
+                        expected: checksum,
+                        computed: computed_checksum,
+                    }))
+                } else {
+                    buffer.write_all(&chunk_data)?;
+
+                    port.write_u8(0xFF)?;
+
+                    emit(Effect::Progress {
+                        done: buffer.get_ref().len(),
+                        total: data.len(),
+                    });
+
+                    State::ReadingBack {
+                        data,
+                        buffer,
+                        purpose,
+                    }
+                }
+            }
+            (
+                State::ReadingBack {
+                    data,
+                    buffer,
+                    purpose:
+                        ReadBackPurpose::WriteOnceCheck {
+                            verify,
+                            verify_with_read,
+                        },
+                },
+                Packet::ReadEnd,
+            ) => {
+                let current_data = buffer.into_inner();
+
+                emit(Effect::ProgressEnd);
+
+                let violation =
+                    data.iter().zip(&current_data).enumerate().find(
+                        |&(_, (&attempted, &current))| current != 0xFF && current != attempted,
+                    );
+
+                if let Some((address, (&attempted, &current))) = violation {
+                    State::Finished(Err(Error::WriteOnceViolation {
+                        address: (opts.command.base_address() + address).try_into().unwrap(),
+                        current,
+                        attempted,
+                    }))
+                } else {
+                    emit(Effect::PrintLn(
+                        "All target cells are blank or already hold the new data.".to_owned(),
+                    ));
+
+                    State::AwaitingWrite {
+                        data,
+                        verify,
+                        verify_with_read,
+                    }
+                }
+            }
+            (
+                State::ReadingBack {
+                    data,
+                    buffer,
+                    purpose: ReadBackPurpose::Verify,
+                },
+                Packet::ReadEnd,
+            ) => {
+                let read_data = buffer.into_inner();
+
+                let mismatches = data
+                    .iter()
+                    .zip(&read_data)
+                    .filter(|(expected, found)| expected != found)
+                    .count()
+                    + data.len().abs_diff(read_data.len());
+
+                emit(Effect::ProgressEnd);
+                emit(Effect::TransferStats {
+                    bytes_transferred: data.len(),
+                    mismatches,
+                });
+
+                if mismatches == 0 {
+                    emit(Effect::PrintLn("No mismatches found.".to_owned()));
+                    opts.command.after_write(data.len(), data.len(), 0, emit)
+                } else {
+                    emit(Effect::PrintLn(format!("{} mismatches found.", mismatches)));
+                    State::Finished(Err(Error::VerificationFailed { mismatches }))
+                }
+            }
+
+            (
+                State::SpotChecking {
+                    check,
+                    checks,
+                    failed,
+                    ..
+                },
+                Packet::Chunk {
+                    data: chunk_data,
+                    checksum,
+                },
+            ) => {
+                let computed_checksum = protocol::calculate_checksum(&chunk_data);
+
+                if checksum != computed_checksum {
+                    State::Finished(Err(Error::ChecksumMismatch {
+                        expected: checksum,
+                        computed: computed_checksum,
+                    }))
+                } else {
+                    port.write_u8(0xFF)?;
+
+                    State::SpotChecking {
+                        check,
+                        found: chunk_data.first().copied(),
+                        checks,
+                        failed,
+                    }
+                }
+            }
+            (
+                State::SpotChecking {
+                    check,
+                    found,
+                    checks,
+                    mut failed,
+                },
+                Packet::ReadEnd,
+            ) => {
+                let result = match found {
+                    Some(found) if found == check.expected => "passed".to_owned(),
+                    Some(found) => format!("FAILED (found 0x{:02X})", found),
+                    None => "FAILED (nothing was read)".to_owned(),
+                };
+
+                if found != Some(check.expected) {
+                    failed += 1;
+                }
+
+                emit(Effect::PrintLn(format!(
+                    "Spot check at {} = 0x{:02X}: {}",
+                    opts.display_addr(check.address),
+                    check.expected,
+                    result
+                )));
+
+                if !checks.is_empty() {
+                    State::AwaitingSpotCheck { checks, failed }
+                } else if failed == 0 {
+                    State::Finished(Ok(()))
+                } else {
+                    State::Finished(Err(Error::SpotCheckFailed { failed }))
+                }
+            }
+
+            (
+                State::Erasing {
+                    started_at,
+                    data,
+                    verify,
+                    verify_with_read,
+                },
+                Packet::Ack,
+            ) => {
+                emit(Effect::ProgressEnd);
+                emit(Effect::PrintLn(format!(
+                    "EEPROM erased in {:.1}s.",
+                    started_at.elapsed().as_secs_f64()
+                )));
+
+                State::AwaitingWrite {
+                    data,
+                    verify,
+                    verify_with_read,
+                }
+            }
+
+            (
+                State::BlankChecking {
+                    start,
+                    progress,
+                    total,
+                    mut non_blank,
+                    mut non_blank_count,
+                },
+                Packet::Chunk {
+                    data: chunk_data,
+                    checksum,
+                },
+            ) => {
+                let computed_checksum = protocol::calculate_checksum(&chunk_data);
+
+                if checksum != computed_checksum {
+                    State::Finished(Err(Error::ChecksumMismatch {
+                        expected: checksum,
+                        computed: computed_checksum,
+                    }))
+                } else {
+                    port.write_u8(0xFF)?;
+
+                    let limit = match opts.command {
+                        UserCommand::BlankCheck { report_limit, .. } => report_limit,
+                        _ => 0,
+                    };
+
+                    for (i, _) in chunk_data.iter().enumerate().filter(|&(_, &b)| b != 0xFF) {
+                        non_blank_count += 1;
+
+                        if non_blank.len() < limit {
+                            non_blank.push(start + (progress + i) as u16);
+                        }
+                    }
+
+                    let progress = progress + chunk_data.len();
+                    emit(Effect::Progress {
+                        done: progress,
+                        total,
+                    });
+
+                    State::BlankChecking {
+                        start,
+                        progress,
+                        total,
+                        non_blank,
+                        non_blank_count,
+                    }
+                }
+            }
+            (
+                State::BlankChecking {
+                    progress,
+                    non_blank,
+                    non_blank_count,
+                    ..
+                },
+                Packet::ReadEnd,
+            ) => {
+                emit(Effect::ProgressEnd);
+                emit(Effect::TransferStats {
+                    bytes_transferred: progress,
+                    mismatches: non_blank_count,
+                });
+
+                if non_blank_count == 0 {
+                    emit(Effect::PrintLn(format!(
+                        "All {} bytes are blank.",
+                        progress
+                    )));
+
+                    State::Finished(Ok(()))
+                } else {
+                    emit(Effect::PrintLn(format!(
+                        "{} of {} bytes are not blank. First ones:",
+                        non_blank_count, progress
+                    )));
+
+                    for address in non_blank {
+                        emit(Effect::PrintLn(format!("  {}", opts.display_addr(address))));
+                    }
+
+                    State::Finished(Err(Error::NotBlank {
+                        count: non_blank_count,
+                    }))
+                }
+            }
+
+            (
+                State::QueryingLimits,
+                Packet::FirmwareLimits {
+                    max_chunk_size,
+                    max_page_size,
+                },
+            ) => {
+                let page_size = opts.chip.spec().page_size;
+
+                emit(Effect::PrintLn(format!(
+                    "Firmware takes chunks of up to {} bytes and pages of up to {} bytes.",
+                    max_chunk_size, max_page_size
+                )));
+
+                if opts.chunk_size > max_chunk_size {
+                    State::Finished(Err(Error::ChunkSizeExceedsFirmware {
+                        chunk_size: opts.chunk_size,
+                        max: max_chunk_size,
+                    }))
+                } else if page_size > max_page_size.into() {
+                    State::Finished(Err(Error::PageSizeExceedsFirmware {
+                        chip: opts.chip.name(),
+                        page_size,
+                        max: max_page_size,
+                    }))
+                } else {
+                    // The board sends another Ready once it is done answering
+                    State::LimitsChecked
+                }
+            }
+            (State::LimitsChecked, Packet::Ready) => {
+                // An empty write is harmless and exercises a full command round trip
+                protocol::send_command(port, 0x01)?;
+                port.write_u8(0x00)?;
+                port.write_u8(0x00)?;
+
+                State::CheckingFirmware
+            }
+            (State::CheckingFirmware, Packet::ChunkRequest) => {
                 port.write_u8(0x00)?;
 
-                effects.push(Effect::ProgressEnd);
+                emit(Effect::PrintLn(
+                    "Firmware check passed: the board responded as expected.".to_owned(),
+                ));
+
+                State::Finished(Ok(()))
+            }
+            (State::CheckingFirmware, packet) => {
+                State::Finished(Err(Error::IncompatibleFirmware { packet }))
+            }
+
+            (
+                State::QueryingVersion,
+                Packet::FirmwareVersion {
+                    major,
+                    minor,
+                    patch,
+                },
+            ) => {
+                let version = FirmwareVersion {
+                    major,
+                    minor,
+                    patch,
+                };
+
+                emit(Effect::PrintLn(format!("Firmware version {}", version)));
+
+                match opts.required_version {
+                    Some(ref required) if !required.matches(version) => {
+                        State::Finished(Err(Error::UnsupportedFirmwareVersion {
+                            version,
+                            required: required.clone(),
+                        }))
+                    }
+                    _ if opts.command == UserCommand::FirmwareVersion => State::Finished(Ok(())),
+                    // The board sends another Ready once it is done answering
+                    _ => State::VersionChecked,
+                }
+            }
+
+            (state, packet) => State::Finished(Err(Error::UnexpectedPacket {
+                state_kind: state.kind(),
+                packet,
+            })),
+        };
+
+        tracing::Span::current()
+            .record("next_state_kind", tracing::field::debug(next_state.kind()));
+
+        Ok(next_state)
+    }
+
+    /// Queries the firmware version first if the options need it, and starts
+    /// the operation right away otherwise.
+    fn begin(
+        port: &mut impl SerialIO,
+        opts: &UserOptions,
+        emit: &mut dyn FnMut(Effect),
+    ) -> Result<State, Error> {
+        if opts.required_version.is_some()
+            || matches!(
+                opts.command,
+                UserCommand::FirmwareVersion | UserCommand::CheckFirmware
+            )
+        {
+            protocol::send_command(port, 0x09)?;
+            return Ok(State::QueryingVersion);
+        }
+
+        Self::start_command(port, opts, emit)
+    }
+
+    /// Starts the operation the user asked for, once the board is ready for a
+    /// command.
+    fn start_command(
+        port: &mut impl SerialIO,
+        opts: &UserOptions,
+        emit: &mut dyn FnMut(Effect),
+    ) -> Result<State, Error> {
+        let next_state = match opts.command {
+            UserCommand::Read {
+                ref out_filename,
+                start,
+                end,
+                chunk_size,
+                ref write_back,
+                format,
+                ref symbol_name,
+                prepend_bom,
+                ..
+            } => {
+                if end <= start {
+                    return Err(Error::InvalidRegionBounds { start, end });
+                }
+
+                let mut out_file = DumpFile::create(out_filename)?;
+                format.begin(
+                    &mut out_file,
+                    (end - start).into(),
+                    symbol_name,
+                    prepend_bom,
+                )?;
+
+                if chunk_size != 0 && (end - start) % u16::from(chunk_size) != 0 {
+                    emit(Effect::OperationWarning(format!(
+                        "Read range is not a multiple of the chunk size ({} bytes)",
+                        chunk_size
+                    )));
+                }
+
+                emit(Effect::PrintLn(format!(
+                    "Initiating EEPROM read ({} to {})...",
+                    opts.display_addr(start),
+                    opts.display_addr(end)
+                )));
+
+                protocol::send_command(port, 0x00)?;
+                port.write_u16(start)?;
+                port.write_u16(end)?;
+                port.write_u8(chunk_size)?;
+
+                let effective_chunk_size = match chunk_size {
+                    0 => DEFAULT_CHUNK_SIZE,
+                    n => n,
+                };
+
+                State::Reading {
+                    start,
+                    chunk_size: effective_chunk_size.into(),
+                    progress: 0,
+                    total: (end - start).into(),
+                    recovery: ReadRecovery::default(),
+                    out_file,
+                    out_path: out_filename.clone(),
+                    write_back: write_back.is_some(),
+                    started_at: Instant::now(),
+                    deadline: Some(Instant::now() + opts.read_timeout),
+                    checksum: RunningChecksum::default(),
+                }
+            }
+            UserCommand::Write {
+                verify,
+                verify_with_read,
+                write_once,
+                erase_before_write,
+                offset,
+                start,
+                end,
+                dry_run,
+                verify_only,
+                ..
+            } => {
+                emit(Effect::PrintLn("Initiating EEPROM write...".to_owned()));
+
+                let data = opts.write_image(emit)?.unwrap();
+
+                let is_partial = start != 0 || end.is_some();
+                let first_address = start.max(offset);
+                let last_address = usize::from(start) + data.len();
+
+                let capacity = opts.chip.spec().capacity_bytes as usize;
+
+                if !is_partial && data.len() < capacity {
+                    emit(Effect::OperationWarning(format!(
+                        "Input is {} bytes, so it does not fill the {} ({} bytes)",
+                        data.len(),
+                        opts.chip.name(),
+                        capacity
+                    )));
+                }
+
+                let action = if dry_run {
+                    "Would write"
+                } else if verify_only {
+                    "Verifying"
+                } else {
+                    "Writing"
+                };
+
+                emit(Effect::PrintLn(format!(
+                    "{} {} to {}...",
+                    action,
+                    opts.display_addr(first_address),
+                    opts.display_addr(last_address.try_into().unwrap())
+                )));
+
+                if dry_run {
+                    emit(Effect::DryRunSummary {
+                        file_size: data.len(),
+                        checksum: protocol::calculate_checksum(&data),
+                    });
 
-                if mismatches.is_empty() {
-                    effects.push(Effect::PrintLn("No mismatches found.".to_owned()));
                     State::Finished(Ok(()))
-                } else {
-                    effects.push(Effect::PrintLn(format!(
-                        "{} mismatches found.",
-                        mismatches.len()
-                    )));
+                } else if verify_only {
+                    protocol::send_command(port, 0x02)?;
+                    port.write_u8(opts.command.fixes_after_write().into())?;
 
-                    if fix {
-                        State::Fixing {
-                            mismatches,
-                            current: 0,
-                        }
-                    } else {
-                        State::Finished(Ok(()))
+                    State::Verifying {
+                        current_byte: 0,
+                        data,
+                        mismatches: vec![],
+                        fix: opts.command.fixes_after_write(),
+                        retry: ChunkRetry::new(opts.max_retries),
+                        started_at: Instant::now(),
+                        deadline: Some(Instant::now() + opts.verify_timeout),
+                    }
+                } else if erase_before_write {
+                    let erase_time = opts.chip.spec().erase_time();
+
+                    if opts.erase_timeout < erase_time {
+                        emit(Effect::Warning(format!(
+                            "The erase timeout ({}s) is shorter than the {:.1}s the {} may take to erase",
+                            opts.erase_timeout.as_secs(),
+                            erase_time.as_secs_f64(),
+                            opts.chip.name()
+                        )));
+                    }
+
+                    emit(Effect::PrintLn("Erasing EEPROM...".to_owned()));
+
+                    protocol::send_erase(
+                        port,
+                        opts.chip.end_address(),
+                        opts.chip.spec().page_size.try_into().unwrap(),
+                    )?;
+
+                    State::Erasing {
+                        started_at: Instant::now(),
+                        data,
+                        verify,
+                        verify_with_read,
                     }
+                } else if write_once {
+                    emit(Effect::PrintLn(
+                        "Checking that only blank cells will be written...".to_owned(),
+                    ));
+
+                    Self::start_read_back(
+                        port,
+                        data,
+                        ReadBackPurpose::WriteOnceCheck {
+                            verify,
+                            verify_with_read,
+                        },
+                        opts,
+                    )?
+                } else {
+                    Self::start_write(port, data, verify, verify_with_read, opts)?
                 }
             }
-            (
-                State::Verifying {
-                    mut current_byte,
-                    data,
-                    mismatches,
-                    fix,
-                },
-                Packet::ChunkRequest,
-            ) => {
-                protocol::send_data_chunk(&mut *port, &data, &mut current_byte)?;
+            UserCommand::Verify {
+                ref in_filename,
+                fix,
+                ..
+            } => {
+                let data = std::fs::read(in_filename)?;
 
-                effects.push(Effect::VerifyProgress {
-                    done: current_byte,
-                    total: data.len(),
-                    mismatches: mismatches.len(),
-                });
+                emit(Effect::PrintLn(
+                    "Initiating EEPROM verification...".to_owned(),
+                ));
+
+                protocol::send_command(port, 0x02)?;
+                port.write_u8(fix.into())?;
 
                 State::Verifying {
-                    current_byte,
+                    current_byte: 0,
                     data,
-                    mismatches,
+                    mismatches: vec![],
                     fix,
+                    retry: ChunkRetry::new(opts.max_retries),
+                    started_at: Instant::now(),
+                    deadline: Some(Instant::now() + opts.verify_timeout),
                 }
             }
+            UserCommand::BlankCheck { start, end, .. } => {
+                emit(Effect::PrintLn(format!(
+                    "Checking that {} to {} is blank...",
+                    opts.display_addr(start),
+                    opts.display_addr(end)
+                )));
 
-            (
-                State::Fixing {
-                    mismatches,
-                    current,
-                },
-                Packet::ByteRequest,
-            ) if current >= mismatches.len() => {
-                port.write_u16(0xFFFF)?;
+                protocol::send_command(port, 0x00)?;
+                port.write_u16(start)?;
+                port.write_u16(end)?;
+                port.write_u8(READ_BACK_CHUNK_SIZE)?;
 
-                effects.push(Effect::ProgressEnd);
-                effects.push(Effect::PrintLn("Mismatches fixed successfully.".to_owned()));
+                State::BlankChecking {
+                    start,
+                    progress: 0,
+                    total: (end - start).into(),
+                    non_blank: vec![],
+                    non_blank_count: 0,
+                }
+            }
+            UserCommand::Fill {
+                value,
+                start,
+                end,
+                verify,
+            } => {
+                emit(Effect::PrintLn(format!(
+                    "Filling {} to {} with 0x{:02X}...",
+                    opts.display_addr(start),
+                    opts.display_addr(end),
+                    value
+                )));
 
-                State::Finished(Ok(()))
+                let data = vec![value; (end - start).into()];
+                Self::start_write(port, data, verify, false, opts)?
             }
-            (
-                State::Fixing {
-                    mismatches,
-                    mut current,
-                },
-                Packet::ByteRequest,
-            ) => {
-                let mismatch = &mismatches[current];
-                current += 1;
+            UserCommand::FirmwareVersion => unreachable!("the version is queried from Idle"),
+            UserCommand::CheckFirmware => {
+                emit(Effect::PrintLn(format!(
+                    "Checking firmware against the {}...",
+                    opts.chip.name()
+                )));
 
-                port.write_u16(mismatch.address)?;
-                port.write_u8(mismatch.expected)?;
+                protocol::send_command(port, 0x0A)?;
 
-                effects.push(Effect::Progress {
-                    total: mismatches.len(),
-                    done: current,
-                });
-                State::Fixing {
-                    mismatches,
-                    current,
+                State::QueryingLimits
+            }
+        };
+
+        Ok(next_state)
+    }
+
+    /// Handles a read that timed out, by skipping the current chunk if the
+    /// options allow it.
+    pub fn recover_from_timeout(
+        self,
+        port: &mut impl SerialIO,
+        opts: &UserOptions,
+        emit: &mut dyn FnMut(Effect),
+    ) -> Result<State, Error> {
+        let State::Reading {
+            start,
+            chunk_size,
+            progress,
+            total,
+            mut recovery,
+            mut out_file,
+            out_path,
+            write_back,
+            started_at,
+            mut checksum,
+            ..
+        } = self
+        else {
+            return Err(Error::ReadTimeout);
+        };
+
+        let len = chunk_size.min(total - progress);
+
+        if len == 0 || recovery.consecutive_skips >= opts.timeout_recovery_reads {
+            return Err(Error::ReadTimeout);
+        }
+
+        // A chunk that arrives late must not be taken for the next one. The
+        // skip also acknowledges a chunk the board already sent, so it moves on
+        // either way.
+        let drained = serial::drain_input(port, RECOVERY_QUIET_TIME)?;
+
+        if drained > 0 {
+            tracing::debug!(drained, "discarded late chunk data");
+        }
+
+        port.write_u8(CHUNK_SKIP)?;
+
+        let placeholder = vec![RECOVERY_PLACEHOLDER; len];
+        checksum.update(&placeholder);
+        opts.command.file_format().write_chunk(
+            &mut out_file,
+            start + progress as u16,
+            &placeholder,
+            opts.command.record_size(),
+            opts.command.srec_record_type(),
+        )?;
+
+        let range = progress..progress + len;
+        recovery.consecutive_skips += 1;
+
+        match recovery.recovered.last_mut() {
+            Some(last) if last.end == range.start => last.end = range.end,
+            _ => recovery.recovered.push(range),
+        }
+
+        emit(Effect::Warning(format!(
+            "Timed out reading {} bytes at {}, filling them with 0x{:02X}",
+            len,
+            opts.display_addr(start + progress as u16),
+            RECOVERY_PLACEHOLDER
+        )));
+
+        emit(Effect::Progress {
+            done: progress + len,
+            total,
+        });
+
+        if write_back {
+            emit(Effect::WriteBackChunk(placeholder));
+        }
+
+        Ok(State::Reading {
+            start,
+            chunk_size,
+            progress: progress + len,
+            total,
+            recovery,
+            out_file,
+            out_path,
+            write_back,
+            started_at,
+            checksum,
+            deadline: None,
+        })
+    }
+
+    /// The deadline for the board to answer the command that started the
+    /// current read, write or verify, if it has not answered yet, along with
+    /// the name and timeout of the operation.
+    fn start_deadline(&self, opts: &UserOptions) -> Option<(Instant, &'static str, Duration)> {
+        match *self {
+            State::Reading {
+                deadline: Some(deadline),
+                ..
+            } => Some((deadline, "read", opts.read_timeout)),
+            State::Writing {
+                deadline: Some(deadline),
+                ..
+            } => Some((deadline, "write", opts.write_timeout)),
+            State::Verifying {
+                deadline: Some(deadline),
+                ..
+            } => Some((deadline, "verification", opts.verify_timeout)),
+            _ => None,
+        }
+    }
+
+    /// Keeps waiting on a read that timed out before the board answered the
+    /// command that started the operation, until its deadline passes.
+    fn wait_for_start(self, opts: &UserOptions) -> Result<State, Error> {
+        let Some((deadline, operation, timeout)) = self.start_deadline(opts) else {
+            return Err(Error::ReadTimeout);
+        };
+
+        if Instant::now() >= deadline {
+            return Err(Error::StartTimeout {
+                operation,
+                ms: timeout.as_millis().try_into().unwrap_or(u64::MAX),
+            });
+        }
+
+        Ok(self)
+    }
+
+    /// Keeps waiting on a read that timed out while the board is erasing,
+    /// until the erase timeout runs out.
+    fn wait_for_erase(
+        self,
+        opts: &UserOptions,
+        emit: &mut dyn FnMut(Effect),
+    ) -> Result<State, Error> {
+        let State::Erasing { started_at, .. } = self else {
+            return Err(Error::ReadTimeout);
+        };
+
+        let elapsed = started_at.elapsed();
+
+        if elapsed >= opts.erase_timeout {
+            return Err(Error::EraseTimeout {
+                secs: opts.erase_timeout.as_secs(),
+            });
+        }
+
+        emit(Effect::Erasing { elapsed });
+
+        Ok(self)
+    }
+
+    /// Stops the operation early. Writes and verifications are stopped by the
+    /// board once it asks for the next chunk, while reads keep what was read so
+    /// far in a `.partial` file.
+    pub fn abort(self, emit: &mut dyn FnMut(Effect)) -> Result<State, Error> {
+        emit(Effect::ProgressEnd);
+
+        let next_state = match self {
+            State::Writing {
+                current_byte, data, ..
+            } => State::Aborting {
+                done: current_byte,
+                total: data.len(),
+                action: "written",
+            },
+            State::Verifying {
+                current_byte, data, ..
+            } => State::Aborting {
+                done: current_byte,
+                total: data.len(),
+                action: "verified",
+            },
+            State::Reading {
+                progress,
+                total,
+                mut out_file,
+                out_path,
+                ..
+            } => {
+                out_file.flush()?;
+
+                if let DumpFile::Stdout(_) = out_file {
+                    emit(Effect::PrintLn(format!(
+                        "Aborted after {}/{} bytes read.",
+                        progress, total
+                    )));
+
+                    return Ok(State::Finished(Err(Error::Interrupted)));
                 }
+
+                drop(out_file);
+
+                let mut partial_path = out_path.clone().into_os_string();
+                partial_path.push(".partial");
+                std::fs::rename(&out_path, &partial_path)?;
+
+                emit(Effect::PrintLn(format!(
+                    "Aborted after {}/{} bytes read. Kept them in {:?}.",
+                    progress,
+                    total,
+                    PathBuf::from(partial_path)
+                )));
+
+                State::Finished(Err(Error::Interrupted))
             }
+            _ => State::Finished(Err(Error::Interrupted)),
+        };
 
-            (state, packet) => State::Finished(Err(Error::UnexpectedPacket {
-                state_kind: state.kind(),
-                packet,
-            })),
+        Ok(next_state)
+    }
+
+    fn start_write(
+        port: &mut impl SerialIO,
+        data: Vec<u8>,
+        verify: bool,
+        verify_with_read: bool,
+        opts: &UserOptions,
+    ) -> Result<State, Error> {
+        // Reading back replaces the on-board verification
+        let verify = verify && !verify_with_read;
+
+        let mut flags = 0;
+
+        if verify {
+            flags |= protocol::WRITE_VERIFY;
+        }
+
+        if opts.command.page_write() {
+            flags |= protocol::WRITE_PAGES;
+        }
+
+        protocol::send_command(port, 0x01)?;
+        port.write_u8(flags)?;
+        port.write_u8(opts.command.fixes_after_write().into())?;
+
+        Ok(State::Writing {
+            current_byte: 0,
+            queue: opts.write_chunk_offsets(data.len()),
+            board_address: 0,
+            pages_skipped: 0,
+            data,
+            verify,
+            verify_with_read,
+            retry: ChunkRetry::new(opts.max_retries),
+            started_at: Instant::now(),
+            deadline: Some(Instant::now() + opts.write_timeout),
+        })
+    }
+
+    fn start_read_back(
+        port: &mut impl SerialIO,
+        data: Vec<u8>,
+        purpose: ReadBackPurpose,
+        opts: &UserOptions,
+    ) -> Result<State, Error> {
+        let base = opts.command.base_address();
+
+        protocol::send_command(port, 0x00)?;
+        port.write_u16(base.try_into().unwrap())?;
+        port.write_u16((base + data.len()).try_into().unwrap())?;
+        port.write_u8(READ_BACK_CHUNK_SIZE)?;
+
+        Ok(State::ReadingBack {
+            buffer: Cursor::new(Vec::with_capacity(data.len())),
+            data,
+            purpose,
+        })
+    }
+}
+
+/// Drives a [`State`] machine by reading packets from a port until it
+/// finishes. Each step passes the new state's effects to a callback as they
+/// happen, while iterating yields the kind of each new state alongside them.
+///
+/// If the machine finishes with an error, the final step is followed by that
+/// error.
+#[derive(Debug)]
+pub struct StateDriver<'a, IO: SerialIO> {
+    state: Option<State>,
+    pending_error: Option<Error>,
+    port: &'a mut IO,
+    opts: &'a UserOptions,
+    last_opcode: Option<u8>,
+}
+
+impl<'a, IO: SerialIO> StateDriver<'a, IO> {
+    pub fn new(state: State, port: &'a mut IO, opts: &'a UserOptions) -> Self {
+        Self {
+            state: Some(state),
+            pending_error: None,
+            last_opcode: None,
+            port,
+            opts,
+        }
+    }
+
+    pub fn port_mut(&mut self) -> &mut IO {
+        self.port
+    }
+
+    /// Starts the operation over, waiting for the board to become ready.
+    /// Reopening the port resets the board, which forgets how far the
+    /// operation got, so it cannot be resumed where it stopped.
+    pub fn restart(&mut self) {
+        self.state = Some(State::Idle);
+        self.pending_error = None;
+        self.last_opcode = None;
+    }
+
+    /// Aborts the running operation, passing the effects of doing so to
+    /// `emit`. The driver keeps going until the board acknowledges it, if
+    /// needed.
+    pub fn abort(&mut self, emit: &mut dyn FnMut(Effect)) -> Result<(), Error> {
+        let Some(state) = self.state.take() else {
+            return Ok(());
+        };
+
+        match state.abort(emit)? {
+            State::Finished(result) => self.pending_error = result.err(),
+            state => self.state = Some(state),
+        }
+
+        Ok(())
+    }
+
+    /// Reads the next packet and moves to the next state, returning its kind.
+    /// Returns `None` once the machine has finished.
+    pub fn step(&mut self, emit: &mut dyn FnMut(Effect)) -> Option<Result<StateKind, Error>> {
+        if let Some(err) = self.pending_error.take() {
+            return Some(Err(err));
+        }
+
+        self.state.as_ref()?;
+
+        // The state is only taken once a packet has been read, so that the
+        // timeout handlers below still see it.
+        let packet = protocol::read_packet(self.port).map_err(|err| err.after(self.last_opcode));
+
+        let step = match packet.map_err(Error::from) {
+            Ok(packet) => {
+                self.last_opcode = Some(packet.opcode());
+                self.state
+                    .take()?
+                    .transition(packet, self.port, self.opts, emit)
+            }
+            // Bytes left over from before the board reset may not even form
+            // a packet
+            Err(Error::Protocol(ProtocolError::InvalidPacketOpcode { .. }))
+                if matches!(self.state, Some(State::Idle | State::Settling { .. })) =>
+            {
+                Ok(State::Idle)
+            }
+            Err(Error::ReadTimeout) if matches!(self.state, Some(State::Erasing { .. })) => {
+                self.state.take()?.wait_for_erase(self.opts, emit)
+            }
+            Err(Error::ReadTimeout) if matches!(self.state, Some(State::Negotiating { .. })) => {
+                Err(Error::NegotiationTimeout {
+                    ms: NEGOTIATION_TIMEOUT
+                        .as_millis()
+                        .try_into()
+                        .unwrap_or(u64::MAX),
+                })
+            }
+            Err(Error::ReadTimeout)
+                if self
+                    .state
+                    .as_ref()
+                    .is_some_and(|state| state.start_deadline(self.opts).is_some()) =>
+            {
+                self.state.take()?.wait_for_start(self.opts)
+            }
+            Err(Error::ReadTimeout) if self.opts.timeout_recovery_reads > 0 => self
+                .state
+                .take()?
+                .recover_from_timeout(self.port, self.opts, emit),
+            Err(err) => return Some(Err(err)),
+        };
+
+        let next_state = match step {
+            Ok(next_state) => next_state,
+            Err(err) => return Some(Err(err)),
         };
 
-        Ok((next_state, effects))
+        // Whatever the transition sent has to reach the board before waiting
+        // on its answer
+        if let Err(err) = self.port.flush() {
+            return Some(Err(err.into()));
+        }
+
+        let kind = next_state.kind();
+
+        match next_state {
+            State::Finished(result) => self.pending_error = result.err(),
+            state => self.state = Some(state),
+        }
+
+        Some(Ok(kind))
+    }
+}
+
+impl<IO: SerialIO> Iterator for StateDriver<'_, IO> {
+    type Item = Result<(StateKind, Vec<Effect>), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut effects = vec![];
+        let kind = self.step(&mut |effect| effects.push(effect))?;
+
+        Some(kind.map(|kind| (kind, effects)))
+    }
+}
+
+/// Receives every effect produced while running an operation.
+#[cfg(feature = "callback-api")]
+pub type ProgressCallback = Box<dyn Fn(Effect) + Send>;
+
+/// Runs an operation to completion, passing every effect to `cb` instead of
+/// returning them. Useful for embedding the uploader in GUI applications.
+#[cfg(feature = "callback-api")]
+pub fn run_with_callback(
+    opts: UserOptions,
+    mut port: impl SerialIO,
+    cb: ProgressCallback,
+) -> Result<(), Error> {
+    opts.validate()?;
+
+    let mut driver = StateDriver::new(State::Idle, &mut port, &opts);
+
+    while let Some(step) = driver.step(&mut |effect| cb(effect)) {
+        step?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{HANDSHAKE, MockSerialIO};
+
+    /// Host output for getting through [`HANDSHAKE`]: Negotiate and the
+    /// protocol version.
+    const HANDSHAKE_REPLY: [u8; 3] = [protocol::NEGOTIATE, 0x00, 0x03];
+
+    struct Case {
+        name: &'static str,
+        command: serde_json::Value,
+        opts: fn(UserOptions) -> UserOptions,
+        /// Board output after the handshake.
+        board: &'static [u8],
+        check: fn(&Result<(), Error>) -> bool,
+        /// Host output after the handshake reply.
+        sent: &'static [u8],
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("eeprom-programmer-{}-{}", std::process::id(), name))
+    }
+
+    fn run(opts: &UserOptions, port: &mut MockSerialIO) -> Result<(), Error> {
+        let mut driver = StateDriver::new(State::Idle, port, opts);
+
+        while let Some(step) = driver.step(&mut |_| {}) {
+            step?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn transitions() {
+        let in_path = temp_path("transitions.bin");
+        std::fs::write(&in_path, [1, 2, 3, 4]).unwrap();
+        let out_path = temp_path("transitions.out");
+
+        let cases = [
+            Case {
+                name: "read with end before start",
+                command: serde_json::json!({
+                    "read": { "out_filename": out_path, "start": 16, "end": 8 }
+                }),
+                opts: |opts| opts,
+                board: &[],
+                check: |result| {
+                    matches!(
+                        result,
+                        Err(Error::InvalidRegionBounds { start: 16, end: 8 })
+                    )
+                },
+                sent: &[],
+            },
+            Case {
+                name: "firmware check",
+                command: serde_json::json!("check_firmware"),
+                opts: |opts| opts,
+                board: &[
+                    0x0A, 1, 3, 0, 0x00, // Version, then Ready
+                    0x0D, 0xFB, 0xFB, 0x00, // Limits, then Ready
+                    0x04, // Probe answered
+                ],
+                check: |result| result.is_ok(),
+                sent: &[0x09, 0x0A, 0x01, 0x00, 0x00, 0x00],
+            },
+            Case {
+                name: "firmware check with pages too large for the firmware",
+                command: serde_json::json!("check_firmware"),
+                opts: |opts| opts,
+                board: &[0x0A, 1, 3, 0, 0x00, 0x0D, 0xFB, 32],
+                check: |result| {
+                    matches!(
+                        result,
+                        Err(Error::PageSizeExceedsFirmware {
+                            page_size: 64,
+                            max: 32,
+                            ..
+                        })
+                    )
+                },
+                sent: &[0x09, 0x0A],
+            },
+            Case {
+                name: "firmware check with chunks too large for the firmware",
+                command: serde_json::json!("check_firmware"),
+                opts: |opts| opts.with_chunk_size(64).with_chip(Chip::At28c16),
+                board: &[0x0A, 1, 3, 0, 0x00, 0x0D, 32, 32],
+                check: |result| {
+                    matches!(
+                        result,
+                        Err(Error::ChunkSizeExceedsFirmware {
+                            chunk_size: 64,
+                            max: 32
+                        })
+                    )
+                },
+                sent: &[0x09, 0x0A],
+            },
+            Case {
+                name: "firmware check answered with an error",
+                command: serde_json::json!("check_firmware"),
+                opts: |opts| opts,
+                board: &[0x0A, 1, 3, 0, 0x00, 0x0D, 0xFB, 0xFB, 0x00, 0x03],
+                check: |result| {
+                    matches!(
+                        result,
+                        Err(Error::IncompatibleFirmware {
+                            packet: Packet::ReadEnd
+                        })
+                    )
+                },
+                sent: &[0x09, 0x0A, 0x01, 0x00, 0x00],
+            },
+            Case {
+                name: "read of an empty range",
+                command: serde_json::json!({
+                    "read": { "out_filename": out_path, "start": 8, "end": 8 }
+                }),
+                opts: |opts| opts,
+                board: &[],
+                check: |result| matches!(result, Err(Error::InvalidRegionBounds { .. })),
+                sent: &[],
+            },
+            Case {
+                name: "read chunk with a bad checksum",
+                command: serde_json::json!({
+                    "read": { "out_filename": out_path, "start": 0, "end": 2, "chunk_size": 2 }
+                }),
+                opts: |opts| opts,
+                board: &[0x02, 2, 0x00, 0x00, 1, 2],
+                check: |result| {
+                    matches!(
+                        result,
+                        Err(Error::ChecksumMismatch {
+                            expected: 0x0000,
+                            computed: 0x0403
+                        })
+                    )
+                },
+                sent: &[0x00, 0x00, 0x00, 0x00, 0x02, 2],
+            },
+            Case {
+                name: "write chunk resent after the board rejects its checksum",
+                command: serde_json::json!({
+                    "write": { "in_filename": in_path, "verify": false, "fix": false }
+                }),
+                opts: |opts| opts,
+                board: &[0x04, 0x05, 0x14, 0x0A, 0x00, 0x00, 0x04, 0x04],
+                check: |result| result.is_ok(),
+                sent: &[
+                    0x01, 0x00, 0x00, // Write, no flags, no fixing
+                    4, 0x14, 0x0A, 1, 2, 3, 4, // Rejected chunk
+                    4, 0x14, 0x0A, 1, 2, 3, 4,    // Resent chunk
+                    0x00, // No chunks left
+                ],
+            },
+            Case {
+                name: "write chunk rejected with no retries left",
+                command: serde_json::json!({
+                    "write": { "in_filename": in_path, "verify": false, "fix": false }
+                }),
+                opts: |opts| opts.with_max_retries(0),
+                board: &[0x04, 0x05, 0x14, 0x0A, 0x00, 0x00],
+                check: |result| {
+                    matches!(
+                        result,
+                        Err(Error::ChecksumMismatch {
+                            expected: 0x140A,
+                            computed: 0x0000
+                        })
+                    )
+                },
+                sent: &[0x01, 0x00, 0x00, 4, 0x14, 0x0A, 1, 2, 3, 4, 0x00],
+            },
+            Case {
+                name: "write interrupted by the board resetting",
+                command: serde_json::json!({
+                    "write": { "in_filename": in_path, "verify": false, "fix": false }
+                }),
+                opts: |opts| opts,
+                board: &[0x04, 0x00],
+                check: |result| {
+                    matches!(
+                        result,
+                        Err(Error::UnexpectedPacket {
+                            packet: Packet::Ready,
+                            ..
+                        })
+                    )
+                },
+                sent: &[0x01, 0x00, 0x00, 4, 0x14, 0x0A, 1, 2, 3, 4],
+            },
+        ];
+
+        for case in cases {
+            let command = serde_json::from_value(case.command).unwrap();
+            let opts = (case.opts)(UserOptions::default().with_command(command));
+            let mut port = MockSerialIO::new(&[&HANDSHAKE[..], case.board].concat());
+
+            let result = run(&opts, &mut port);
+
+            assert!((case.check)(&result), "{}: got {:?}", case.name, result);
+            assert_eq!(
+                port.writes,
+                [&HANDSHAKE_REPLY[..], case.sent].concat(),
+                "{}: host output",
+                case.name
+            );
+            assert_eq!(port.unread(), 0, "{}: unread board output", case.name);
+        }
+
+        std::fs::remove_file(&in_path).ok();
+        std::fs::remove_file(&out_path).ok();
+    }
+
+    #[test]
+    fn bom_only_starts_text_dumps() {
+        for (format, bom) in [
+            (FileFormat::Binary, &[][..]),
+            (FileFormat::Hex, &UTF8_BOM[..]),
+            (FileFormat::Srec, &UTF8_BOM[..]),
+            (FileFormat::Hexdump, &UTF8_BOM[..]),
+        ] {
+            let mut out = vec![];
+            format.begin(&mut out, 16, "data", true).unwrap();
+            assert_eq!(out, bom, "{:?}", format);
+        }
+
+        let mut out = vec![];
+        FileFormat::CHeader
+            .begin(&mut out, 16, "data", true)
+            .unwrap();
+        assert!(out.starts_with(&UTF8_BOM));
+    }
+
+    #[test]
+    fn srec_record_type_sets_the_address_size() {
+        for (record_type, expected) in [
+            (SrecRecordType::S1, "S105100048653D\nS9030000FC\n"),
+            (SrecRecordType::S2, "S20600100048653C\nS804000000FB\n"),
+            (SrecRecordType::S3, "S3070000100048653B\nS70500000000FA\n"),
+        ] {
+            let mut out = vec![];
+            let format = FileFormat::Srec;
+            format
+                .write_chunk(&mut out, 0x1000, b"He", 16, record_type)
+                .unwrap();
+            format.finish(&mut out, record_type).unwrap();
+
+            let text = String::from_utf8(out).unwrap();
+            assert_eq!(text, expected, "{:?}", record_type);
+            assert_eq!(&srec::parse(&text).unwrap()[0x1000..], b"He");
+        }
     }
 }
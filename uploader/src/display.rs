@@ -0,0 +1,145 @@
+use std::fmt::Write;
+
+/// Formats `data` as a hex dump with `cols` bytes per line, each line starting
+/// with the address of its first byte. With `show_ascii`, printable bytes are
+/// also shown as characters at the end of each line.
+pub fn pretty_print_hex(data: &[u8], base_address: u16, cols: usize, show_ascii: bool) -> String {
+    let cols = cols.max(1);
+    let mut out = String::new();
+
+    for (i, line) in data.chunks(cols).enumerate() {
+        let address = usize::from(base_address) + i * cols;
+        write!(out, "{:04X}: {}", address, hex_bytes(line)).unwrap();
+
+        if show_ascii {
+            // Keep the ASCII column aligned on a short last line
+            let padding = (cols - line.len()) * 3;
+            write!(out, "{:padding$}  |{}|", "", printable(line)).unwrap();
+        }
+
+        out.push('\n');
+    }
+
+    out
+}
+
+/// Formats `bytes` as uppercase hex pairs separated by spaces.
+pub fn hex_bytes(bytes: &[u8]) -> String {
+    let hex: Vec<_> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+    hex.join(" ")
+}
+
+/// Shows the printable ASCII bytes of `line` as characters and every other
+/// byte as a dot.
+fn printable(line: &[u8]) -> String {
+    line.iter()
+        .map(|&b| {
+            if b.is_ascii_graphic() || b == b' ' {
+                char::from(b)
+            } else {
+                '.'
+            }
+        })
+        .collect()
+}
+
+/// Bytes on each line of [`write_hexdump`]'s output.
+const HEXDUMP_LINE_SIZE: usize = 16;
+
+/// Writes `data`, which starts at `address`, in the style of `hexdump -C`:
+/// the address, the bytes in groups of four and their printable characters.
+pub fn write_hexdump(
+    out: &mut impl std::io::Write,
+    address: u16,
+    data: &[u8],
+) -> std::io::Result<()> {
+    for (i, line) in data.chunks(HEXDUMP_LINE_SIZE).enumerate() {
+        let hex: Vec<_> = line
+            .chunks(4)
+            .map(|group| {
+                let bytes: Vec<_> = group.iter().map(|byte| format!("{:02x}", byte)).collect();
+                bytes.join(" ")
+            })
+            .collect();
+
+        let address = address.wrapping_add((i * HEXDUMP_LINE_SIZE) as u16);
+        writeln!(
+            out,
+            "{:04x}:  {:<50}  |{}|",
+            address,
+            hex.join("  "),
+            printable(line)
+        )?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HELLO: [u8; 5] = [0x48, 0x65, 0x6C, 0x6C, 0x6F];
+
+    #[test]
+    fn hex_without_ascii() {
+        assert_eq!(
+            pretty_print_hex(&HELLO, 0x0000, 8, false),
+            "0000: 48 65 6C 6C 6F\n"
+        );
+    }
+
+    #[test]
+    fn hex_pads_ascii_column_on_short_line() {
+        assert_eq!(
+            pretty_print_hex(&HELLO, 0x0000, 8, true),
+            "0000: 48 65 6C 6C 6F           |Hello|\n"
+        );
+    }
+
+    #[test]
+    fn hex_wraps_lines_from_base_address() {
+        assert_eq!(
+            pretty_print_hex(&HELLO, 0x1000, 2, false),
+            "1000: 48 65\n1002: 6C 6C\n1004: 6F\n"
+        );
+    }
+
+    #[test]
+    fn hex_shows_unprintable_bytes_as_dots() {
+        assert_eq!(
+            pretty_print_hex(&[0x00, 0x41, 0x20, 0xFF], 0x0000, 4, true),
+            "0000: 00 41 20 FF  |.A .|\n"
+        );
+    }
+
+    #[test]
+    fn hex_of_empty_data_is_empty() {
+        assert_eq!(pretty_print_hex(&[], 0x0000, 8, true), "");
+    }
+
+    #[test]
+    fn hex_treats_zero_columns_as_one() {
+        assert_eq!(
+            pretty_print_hex(&HELLO[..2], 0x0000, 0, false),
+            "0000: 48\n0001: 65\n"
+        );
+    }
+
+    #[test]
+    fn hex_bytes_are_space_separated() {
+        assert_eq!(hex_bytes(&[0x0A, 0xFF, 0x00]), "0A FF 00");
+        assert_eq!(hex_bytes(&[]), "");
+    }
+
+    #[test]
+    fn hexdump_matches_hexdump_c() {
+        let mut out = vec![];
+        write_hexdump(&mut out, 0x0010, &HELLO).unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!("0010:  {:<50}  |Hello|\n", "48 65 6c 6c  6f")
+        );
+    }
+}
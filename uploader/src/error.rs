@@ -0,0 +1,322 @@
+use std::{path::PathBuf, process::ExitStatus};
+
+use derive_more::{Display, Error, From};
+
+use crate::{
+    core::{SrecRecordType, StateKind},
+    protocol::{Packet, ProtocolError},
+    serial,
+    version::{FirmwareVersion, VersionReq},
+};
+
+#[derive(Debug, From, Display, Error)]
+#[allow(clippy::enum_variant_names)] // `BoardError` reads better than `Board`
+pub enum Error {
+    #[display("I/O error: {_0}")]
+    IO(#[from] std::io::Error),
+
+    #[from(skip)]
+    Protocol(ProtocolError),
+
+    #[display("Arduino firmware error (0x{code:02X}): {message}")]
+    BoardError { code: u8, message: String },
+
+    #[display("Board sent an invalid opcode: {_0}")]
+    InvalidOpcode(#[error(not(source))] u8),
+
+    #[display("Received an unexpected packet (state: {state_kind:?}, packet: {packet})")]
+    UnexpectedPacket {
+        state_kind: StateKind,
+        packet: Packet,
+    },
+
+    #[display("Checksum mismatch (expected = 0x{expected:04X}, computed = 0x{computed:04X})")]
+    ChecksumMismatch { expected: u16, computed: u16 },
+
+    #[display("Memory region bounds must be valid (start = 0x{start:04X}, end = 0x{end:04X})")]
+    #[from(skip)]
+    InvalidRegionBounds { start: u16, end: u16 },
+
+    #[display("File not found: {}", _0.display())]
+    #[from(skip)]
+    FileNotFound(#[error(not(source))] PathBuf),
+
+    #[display("File ({size} bytes) is larger than the EEPROM ({capacity} bytes)")]
+    #[from(skip)]
+    FileTooLarge { size: usize, capacity: usize },
+
+    #[display(
+        "File ({file_size} bytes) is larger than the writable address space ({address_space} bytes). Use --trim to adjust."
+    )]
+    #[from(skip)]
+    FileTooLargeForAddress {
+        file_size: usize,
+        address_space: usize,
+    },
+
+    #[display(
+        "File ({file_size} bytes) does not fit at offset 0x{offset:04X} in the address space ({address_space} bytes)"
+    )]
+    #[from(skip)]
+    OffsetExceedsAddressSpace {
+        offset: u16,
+        file_size: usize,
+        address_space: usize,
+    },
+
+    #[display(
+        "{} and {} overlap at 0x{address:04X}",
+        first.display(),
+        second.display()
+    )]
+    #[from(skip)]
+    OverlappingInputs {
+        first: PathBuf,
+        second: PathBuf,
+        address: u16,
+    },
+
+    #[display("End address 0x{end:04X} is past the end of the input ({image_size} bytes)")]
+    #[from(skip)]
+    RegionExceedsInput { end: u16, image_size: usize },
+
+    #[display("Cannot trim to {trim} bytes, as the file is only {file_size} bytes long")]
+    #[from(skip)]
+    TrimExceedsFileSize { trim: usize, file_size: usize },
+
+    #[display("Cannot skip {skip} bytes, as the file is only {file_size} bytes long")]
+    #[from(skip)]
+    SkipExceedsFileSize { skip: usize, file_size: usize },
+
+    #[display("Address 0x{address:04X} exceeds the limit of 0x{limit:04X}")]
+    AddressExceedsLimit { address: usize, limit: u16 },
+
+    #[display("Operation needs {end} bytes, but the {chip} only holds {capacity}")]
+    #[from(skip)]
+    ExceedsChipCapacity {
+        end: usize,
+        chip: &'static str,
+        capacity: usize,
+    },
+
+    #[display("Chunks of {chunk_size} bytes are larger than the firmware's limit of {max}")]
+    #[from(skip)]
+    ChunkSizeExceedsFirmware { chunk_size: u8, max: u8 },
+
+    #[display("The {chip}'s {page_size}-byte pages are larger than the firmware's limit of {max}")]
+    #[from(skip)]
+    PageSizeExceedsFirmware {
+        chip: &'static str,
+        page_size: u16,
+        max: u8,
+    },
+
+    #[display("The {chip} has no page mode, so it cannot be written page by page")]
+    #[from(skip)]
+    PageWriteUnsupported { chip: &'static str },
+
+    #[display(
+        "Input file {algorithm} checksum mismatch (expected {expected}, computed {computed})"
+    )]
+    #[from(skip)]
+    InputChecksumMismatch {
+        expected: String,
+        computed: String,
+        algorithm: String,
+    },
+
+    #[display("Verification found {mismatches} mismatches")]
+    VerificationFailed { mismatches: usize },
+
+    #[display(
+        "Refusing to overwrite address 0x{address:04X} (current = 0x{current:02X}, attempted = 0x{attempted:02X})"
+    )]
+    WriteOnceViolation {
+        address: u16,
+        current: u8,
+        attempted: u8,
+    },
+
+    #[display("The board did not finish erasing the EEPROM within {secs}s")]
+    #[from(skip)]
+    EraseTimeout { secs: u64 },
+
+    #[display("The board did not start the {operation} within {ms} ms")]
+    #[from(skip)]
+    StartTimeout { operation: &'static str, ms: u64 },
+
+    #[display(
+        "The board did not answer protocol negotiation within {ms} ms. Its firmware may be too old; try re-flashing the Arduino."
+    )]
+    #[from(skip)]
+    NegotiationTimeout { ms: u64 },
+
+    #[display("Address 0x{address:04X} is not aligned to the block size ({block_size} bytes)")]
+    #[from(skip)]
+    UnalignedBlockAccess { address: u16, block_size: u16 },
+
+    #[display(
+        "Could not run avrdude at {path:?}. Is it installed? Use --avrdude-path to point to it."
+    )]
+    #[from(skip)]
+    AvrdudeNotFound { path: PathBuf },
+
+    #[display("avrdude failed to flash the firmware ({status})")]
+    #[from(skip)]
+    FirmwareUpdateFailed { status: ExitStatus },
+
+    #[display("Chunk size must be between 1 and {max} bytes (got {chunk_size})")]
+    #[from(skip)]
+    InvalidChunkSize { chunk_size: u8, max: u8 },
+
+    #[display("Invalid Intel HEX file (line {line}): {reason}")]
+    #[from(skip)]
+    InvalidHexRecord { line: usize, reason: String },
+
+    #[display("Invalid S-record file (line {line}): {reason}")]
+    #[from(skip)]
+    InvalidSrecRecord { line: usize, reason: String },
+
+    #[display("C headers can only be written when reading, not used as input")]
+    CHeaderInput,
+
+    #[display("Hex dumps can only be written when reading, not used as input")]
+    HexdumpInput,
+
+    #[display("'{name}' is not a valid C identifier")]
+    #[from(skip)]
+    InvalidSymbolName { name: String },
+
+    #[display("Address 0x{address:04X} does not fit in {record_type:?} records")]
+    #[from(skip)]
+    AddressTooWideForSrec {
+        address: u32,
+        record_type: SrecRecordType,
+    },
+
+    #[display("{count} bytes are not blank")]
+    #[from(skip)]
+    NotBlank { count: usize },
+
+    #[display("{failed} of {count} chips failed to be written")]
+    #[from(skip)]
+    BatchWriteFailed { failed: u32, count: u32 },
+
+    #[display("{failed} spot checks failed")]
+    #[from(skip)]
+    SpotCheckFailed { failed: usize },
+
+    #[display(
+        "Board firmware seems incompatible (received {packet}). Try re-flashing the Arduino."
+    )]
+    IncompatibleFirmware { packet: Packet },
+
+    #[display("Board firmware {version} does not satisfy the required version {required}")]
+    #[from(skip)]
+    UnsupportedFirmwareVersion {
+        version: FirmwareVersion,
+        required: VersionReq,
+    },
+
+    #[display(
+        "Board speaks protocol version {firmware}, but the uploader speaks {host}. Try re-flashing the Arduino."
+    )]
+    #[from(skip)]
+    ProtocolVersionMismatch { host: u16, firmware: u16 },
+
+    #[display("Board firmware does not report its version. Try re-flashing the Arduino.")]
+    #[from(skip)]
+    UnknownFirmwareVersion,
+
+    #[display(
+        "No Arduino found among the serial ports. Check that the board is plugged in, or pass its port with --port."
+    )]
+    #[from(skip)]
+    NoBoardFound,
+
+    #[display(
+        "Several Arduinos found ({}). Pick one with --port.",
+        ports.join(", ")
+    )]
+    #[from(skip)]
+    MultipleBoardsFound { ports: Vec<String> },
+
+    #[display("The board did not answer at any of the baud rates tried ({tried:?})")]
+    #[from(skip)]
+    BaudRateNotFound { tried: Vec<u32> },
+
+    #[display("Invalid session file: {_0}")]
+    InvalidSession(#[from] serde_json::Error),
+
+    #[display("Session diverged from the recording (expected {expected}, found {found})")]
+    #[from(skip)]
+    SessionDivergence { expected: String, found: String },
+
+    #[display("Config error: {_0}")]
+    Config(#[from] Box<ConfigError>),
+
+    #[display("Interrupted by the user")]
+    #[from(skip)]
+    Interrupted,
+
+    #[display("Timed out waiting for the board")]
+    #[from(skip)]
+    ReadTimeout,
+
+    #[display("Serial port disconnected")]
+    #[from(skip)]
+    PortDisconnected,
+
+    #[display("Unknown: {_0}")]
+    #[from(skip)]
+    Unknown(anyhow::Error),
+}
+
+impl From<ProtocolError> for Error {
+    fn from(err: ProtocolError) -> Self {
+        match err {
+            ProtocolError::Unknown(err)
+                if serial::is_disconnect(&err) || serial::is_timeout(&err) =>
+            {
+                err.into()
+            }
+            ProtocolError::IO(err) if serial::is_io_disconnect(&err) => Self::PortDisconnected,
+            ProtocolError::IO(err) if err.kind() == std::io::ErrorKind::TimedOut => {
+                Self::ReadTimeout
+            }
+            err => Self::Protocol(err),
+        }
+    }
+}
+
+impl From<anyhow::Error> for Error {
+    fn from(err: anyhow::Error) -> Self {
+        if err.is::<ProtocolError>() {
+            ProtocolError::from(err).into()
+        } else if serial::is_disconnect(&err) {
+            Self::PortDisconnected
+        } else if serial::is_timeout(&err) {
+            Self::ReadTimeout
+        } else {
+            Self::Unknown(err)
+        }
+    }
+}
+
+#[derive(Debug, From, Display, Error)]
+pub enum ConfigError {
+    #[display("I/O error: {_0}")]
+    IO(#[from] std::io::Error),
+
+    #[display("Invalid JSON: {_0}")]
+    Json(#[from] serde_json::Error),
+
+    #[display("Invalid TOML: {_0}")]
+    Toml(#[from] toml::de::Error),
+
+    #[display("Missing required field: {_0}")]
+    MissingField(#[error(not(source))] String),
+
+    #[display("Invalid options: {_0}")]
+    Invalid(#[from] Error),
+}
@@ -0,0 +1,66 @@
+use std::{
+    path::Path,
+    process::{Command, Stdio},
+    thread,
+    time::Duration,
+};
+
+use crate::error::Error;
+
+/// Microcontroller on the Arduino Mega the firmware is built for.
+const PART: &str = "atmega2560";
+
+/// Programmer protocol spoken by the Mega's stock bootloader.
+const PROGRAMMER: &str = "wiring";
+
+/// Baud rate the bootloader listens at, regardless of the firmware's.
+const BOOTLOADER_BAUD_RATE: u32 = 115200;
+
+/// Flashes an Intel HEX firmware image to the board at `port` by resetting it
+/// into its bootloader and handing the upload off to avrdude.
+pub fn flash(port: &str, firmware_hex: &Path, avrdude_path: Option<&Path>) -> Result<(), Error> {
+    reset_into_bootloader(port)?;
+
+    let avrdude = avrdude_path.unwrap_or(Path::new("avrdude"));
+
+    let status = Command::new(avrdude)
+        .arg("-p")
+        .arg(PART)
+        .arg("-c")
+        .arg(PROGRAMMER)
+        .arg("-P")
+        .arg(port)
+        .arg("-b")
+        .arg(BOOTLOADER_BAUD_RATE.to_string())
+        // The bootloader cannot erase the chip itself
+        .arg("-D")
+        .arg("-U")
+        .arg(format!("flash:w:{}:i", firmware_hex.display()))
+        .stdin(Stdio::null())
+        .status()
+        .map_err(|_| Error::AvrdudeNotFound {
+            path: avrdude.to_owned(),
+        })?;
+
+    if !status.success() {
+        return Err(Error::FirmwareUpdateFailed { status });
+    }
+
+    Ok(())
+}
+
+/// Pulses DTR, which resets the board and leaves it in its bootloader for a
+/// short while.
+fn reset_into_bootloader(port: &str) -> Result<(), Error> {
+    let mut port = serialport::new(port, BOOTLOADER_BAUD_RATE)
+        .open()
+        .map_err(std::io::Error::from)?;
+
+    port.write_data_terminal_ready(false)
+        .map_err(std::io::Error::from)?;
+    thread::sleep(Duration::from_millis(100));
+    port.write_data_terminal_ready(true)
+        .map_err(std::io::Error::from)?;
+
+    Ok(())
+}
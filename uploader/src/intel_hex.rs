@@ -0,0 +1,142 @@
+use std::io::Write;
+
+use crate::{core::ADDRESS_SPACE, error::Error};
+
+const DATA: u8 = 0x00;
+const END_OF_FILE: u8 = 0x01;
+const EXTENDED_SEGMENT_ADDRESS: u8 = 0x02;
+const START_SEGMENT_ADDRESS: u8 = 0x03;
+const EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+const START_LINEAR_ADDRESS: u8 = 0x05;
+
+/// Parses an Intel HEX file into a contiguous image starting at address 0.
+/// Gaps between records are filled with 0xFF.
+pub fn parse(text: &str) -> Result<Vec<u8>, Error> {
+    let mut data = vec![];
+    let mut base_address = 0;
+
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+
+        if line.is_empty() {
+            continue;
+        }
+
+        let invalid = |reason: &str| Error::InvalidHexRecord {
+            line: i + 1,
+            reason: reason.to_owned(),
+        };
+
+        let digits = line
+            .strip_prefix(':')
+            .ok_or_else(|| invalid("missing ':' start code"))?;
+
+        if digits.len() % 2 != 0 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(invalid("expected an even number of hex digits"));
+        }
+
+        let bytes: Vec<u8> = (0..digits.len())
+            .step_by(2)
+            .map(|j| u8::from_str_radix(&digits[j..j + 2], 16).unwrap())
+            .collect();
+
+        let [len, address_high, address_low, kind, ..] = bytes[..] else {
+            return Err(invalid("record is too short"));
+        };
+
+        let len = usize::from(len);
+
+        if bytes.len() != len + 5 {
+            return Err(invalid("byte count does not match the record's length"));
+        }
+
+        if bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) != 0 {
+            return Err(invalid("checksum mismatch"));
+        }
+
+        let payload = &bytes[4..4 + len];
+        let offset = u16::from_be_bytes([address_high, address_low]);
+
+        match kind {
+            DATA => {
+                let start = base_address + usize::from(offset);
+                let end = start + len;
+
+                if end > ADDRESS_SPACE {
+                    return Err(Error::FileTooLargeForAddress {
+                        file_size: end,
+                        address_space: ADDRESS_SPACE,
+                    });
+                }
+
+                if end > data.len() {
+                    data.resize(end, 0xFF);
+                }
+
+                data[start..end].copy_from_slice(payload);
+            }
+            END_OF_FILE => return Ok(data),
+            EXTENDED_SEGMENT_ADDRESS | EXTENDED_LINEAR_ADDRESS => {
+                let [high, low] = payload[..] else {
+                    return Err(invalid("address records must hold 2 bytes"));
+                };
+
+                let shift = if kind == EXTENDED_LINEAR_ADDRESS {
+                    16
+                } else {
+                    4
+                };
+                base_address = usize::from(u16::from_be_bytes([high, low])) << shift;
+            }
+            // Entry points mean nothing to an EEPROM
+            START_SEGMENT_ADDRESS | START_LINEAR_ADDRESS => {}
+            _ => return Err(invalid(&format!("unknown record type 0x{:02X}", kind))),
+        }
+    }
+
+    Err(Error::InvalidHexRecord {
+        line: text.lines().count(),
+        reason: "missing end-of-file record".to_owned(),
+    })
+}
+
+/// Writes `data` as data records of up to `record_size` bytes starting at
+/// `address`. The EEPROM's address space fits in 16 bits, so no extended
+/// address records are needed.
+pub fn write_data(
+    out: &mut impl Write,
+    address: u16,
+    data: &[u8],
+    record_size: u8,
+) -> std::io::Result<()> {
+    let record_size = record_size.max(1).into();
+
+    for (i, chunk) in data.chunks(record_size).enumerate() {
+        let address = address.wrapping_add((i * record_size) as u16);
+        write_record(out, address, DATA, chunk)?;
+    }
+
+    Ok(())
+}
+
+pub fn write_end_of_file(out: &mut impl Write) -> std::io::Result<()> {
+    write_record(out, 0x0000, END_OF_FILE, &[])
+}
+
+fn write_record(out: &mut impl Write, address: u16, kind: u8, data: &[u8]) -> std::io::Result<()> {
+    let [address_high, address_low] = address.to_be_bytes();
+    let header = [data.len() as u8, address_high, address_low, kind];
+
+    let sum = header
+        .iter()
+        .chain(data)
+        .fold(0u8, |sum, &b| sum.wrapping_add(b));
+
+    write!(out, ":")?;
+
+    for byte in header.iter().chain(data) {
+        write!(out, "{:02X}", byte)?;
+    }
+
+    writeln!(out, "{:02X}", sum.wrapping_neg())
+}
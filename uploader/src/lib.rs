@@ -0,0 +1,36 @@
+//! Talks to an Arduino running the programmer firmware to read, write and
+//! verify AT28C EEPROM chips.
+//!
+//! The [`State`] machine drives an operation over any [`SerialIO`], so that it
+//! can be embedded in other tools or run against recorded sessions.
+
+mod c_header;
+pub mod checksum;
+pub mod chips;
+pub mod config;
+pub mod core;
+pub mod display;
+pub mod error;
+pub mod firmware_update;
+mod intel_hex;
+mod mismatch_report;
+pub mod ports;
+pub mod protocol;
+pub mod serial;
+pub mod session;
+mod srec;
+#[cfg(test)]
+mod testing;
+pub mod throughput;
+pub mod timing;
+pub mod trace;
+pub mod version;
+pub mod write_back;
+pub mod write_cache;
+
+pub use crate::{
+    core::{State, UserOptions},
+    error::Error,
+    protocol::{Packet, calculate_checksum, read_packet, send_data_chunk},
+    serial::SerialIO,
+};
@@ -1,36 +1,189 @@
-mod core;
-mod protocol;
-mod serial;
+#[cfg(feature = "tui")]
+mod tui;
 
-use std::{io::Write, path::PathBuf, time::Duration};
+use std::{
+    cell::RefCell,
+    fs::File,
+    io::{BufWriter, Write},
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    process,
+    rc::Rc,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-use clap::{Parser, Subcommand};
+use clap::{
+    ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, error::ErrorKind,
+    parser::ValueSource,
+};
+use signal_hook::consts::SIGINT;
+use tracing_subscriber::EnvFilter;
 
-use crate::{
-    core::{Effect, Error, State, UserCommand, UserOptions},
-    serial::SerialPortIO,
+use eeprom_programmer::{
+    checksum::InputChecksum,
+    chips::Chip,
+    config::{CONFIG_TEMPLATE, Config},
+    core::{
+        ByteMismatch, DEFAULT_START_TIMEOUT_MS, DEFAULT_WRITE_CHUNK_SIZE, Effect, FileFormat,
+        InputSegment, LineEndingMode, OperationKind, SpotCheck, SrecRecordType, State, StateDriver,
+        StateKind, UserCommand, UserOptions, WriteOrder, format_addr,
+    },
+    error::{ConfigError, Error},
+    firmware_update, ports, protocol,
+    serial::{self, ByteOrder, FlowControlMode, SerialIO, SerialPortIO, TcpSerialIO},
+    session::{
+        RecordingSerialIO, ReplaySerialIO, SessionData, SessionObserver, SessionRecorder,
+        load_session,
+    },
+    throughput::{self, RollingThroughput},
+    timing::TimingSerialIO,
+    trace::{self, TracingSerialIO},
+    version::VersionReq,
+    write_back::WriteBack,
+    write_cache::{WriteCache, WriteCacheEntry},
 };
 
 #[derive(Debug, Clone, Subcommand)]
 enum Command {
     /// Dumps the EEPROM data to a file
     Read {
+        /// File to dump the data to, or - for stdout
         #[arg(short, long)]
         out_file: PathBuf,
 
         #[arg(short, long, default_value_t = 0x0000)]
         start: u16,
 
-        #[arg(short, long, default_value_t = 0x8000)]
-        end: u16,
+        /// Address to stop at [default: end of the chip]
+        #[arg(short, long)]
+        end: Option<u16>,
+
+        /// Preferred amount of bytes the board should send per chunk
+        #[arg(long, default_value_t = 32, value_parser = clap::value_parser!(u8).range(1..))]
+        read_chunk_size: u8,
+
+        /// Port of a second board to simultaneously write the read data to
+        #[arg(long, value_name = "PORT")]
+        write_back: Option<String>,
+
+        /// Format to dump the data in (binary, hex, srec, c-header or hexdump)
+        #[arg(long, default_value = "binary")]
+        format: FileFormat,
+
+        /// Data bytes per record for the hex and srec formats
+        #[arg(long, value_name = "N", default_value_t = 16, value_parser = clap::value_parser!(u8).range(1..=252))]
+        record_size: u8,
+
+        /// Name of the array for the c-header format
+        #[arg(long, value_name = "NAME", default_value = "eeprom_data")]
+        symbol_name: String,
+
+        /// Start text formats with a UTF-8 byte order mark (binary dumps are
+        /// left as they are)
+        #[arg(long)]
+        prepend_bom: bool,
+
+        /// Address size of the srec format's records (s1, s2 or s3)
+        #[arg(long, value_name = "TYPE", default_value = "s1")]
+        srec_record_type: SrecRecordType,
     },
 
     /// Writes a file to the EEPROM
     Write {
-        filename: PathBuf,
+        /// Files to write. When there are several, each one needs its own
+        /// --offset, given after it
+        #[arg(required = true)]
+        filename: Vec<PathBuf>,
+
+        /// Format of the file (binary, hex or srec)
+        #[arg(long, default_value = "binary")]
+        format: FileFormat,
 
         #[arg(long)]
         no_verify: bool,
+
+        /// Verify by reading the whole EEPROM back and comparing it on the host
+        #[arg(long, conflicts_with = "no_verify")]
+        verify_with_read: bool,
+
+        /// Report mismatches found while verifying instead of fixing them
+        #[arg(long, conflicts_with = "no_verify")]
+        no_fix: bool,
+
+        /// Order in which to write chunks (sequential, reverse or interleaved)
+        #[arg(long, value_name = "ORDER", default_value = "sequential")]
+        write_order: WriteOrder,
+
+        /// After writing, check that a byte holds the given value (ADDRESS:EXPECTED)
+        #[arg(long, value_name = "CHECK", value_parser = parse_spot_check)]
+        spot_check: Vec<SpotCheck>,
+
+        /// Have the board erase the whole EEPROM before writing
+        #[arg(long, conflicts_with = "write_once")]
+        erase_before_write: bool,
+
+        /// Don't write chunks that are all 0xFF, assuming the chip is blank
+        #[arg(long)]
+        only_data_pages: bool,
+
+        /// Refuse to change any cell that is not blank (0xFF)
+        #[arg(long)]
+        write_once: bool,
+
+        /// Skip the first N bytes of the file
+        #[arg(long, value_name = "N", default_value_t = 0)]
+        input_skip: usize,
+
+        /// First address of the file's image to write
+        #[arg(short, long, default_value_t = 0x0000)]
+        start: u16,
+
+        /// Address to stop writing at (defaults to the end of the file's image)
+        #[arg(short, long)]
+        end: Option<u16>,
+
+        /// Check the file and the connection to the board without writing anything
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Skip writing and only verify the EEPROM against the file
+        #[arg(long, conflicts_with_all = ["no_verify", "verify_with_read", "erase_before_write"])]
+        verify_only: bool,
+
+        /// Write whole pages of the chip at a time, one write cycle per page
+        #[arg(long, conflicts_with = "verify_only")]
+        page_write: bool,
+
+        /// Write the file starting at this address, padding before it with 0xFF
+        #[arg(long, value_name = "ADDRESS", value_parser = parse_number::<u16>)]
+        offset: Vec<u16>,
+
+        /// Only write the first N bytes of the file (after skipping)
+        #[arg(long, value_name = "N")]
+        trim: Option<usize>,
+
+        /// Check the file against this checksum before writing, e.g. sha256:ABCD...
+        #[arg(long, value_name = "ALGORITHM:HEX")]
+        input_checksum: Option<InputChecksum>,
+
+        /// Skip writing if this cache says the same image was already written
+        /// to the same addresses of this chip
+        #[arg(long, value_name = "PATH")]
+        write_cache: Option<PathBuf>,
+
+        /// Write even if the write cache says it is not needed
+        #[arg(long, requires = "write_cache")]
+        force: bool,
+
+        /// Program N chips one after another, asking for the next one in
+        /// between
+        #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..), conflicts_with_all = ["dry_run", "write_cache"])]
+        count: u32,
     },
 
     /// Verifies the EEPROM's data against a file
@@ -39,132 +192,1416 @@ enum Command {
 
         #[arg(long)]
         fix: bool,
+
+        /// Stop verifying once this many mismatches have been found
+        #[arg(long, value_name = "N")]
+        collect_mismatches: Option<NonZeroUsize>,
+
+        /// Write the mismatches, with the bytes around them, to this file
+        /// (as JSON if it ends in .json)
+        #[arg(long, value_name = "FILE")]
+        report: Option<PathBuf>,
+
+        /// Keep verifying every INTERVAL_MS milliseconds until Ctrl+C is pressed
+        #[arg(long, value_name = "INTERVAL_MS", conflicts_with = "fix")]
+        watch: Option<u64>,
     },
+
+    /// Checks that the EEPROM is erased (all bytes are 0xFF)
+    BlankCheck {
+        #[arg(short, long, default_value_t = 0x0000)]
+        start: u16,
+
+        /// Address to stop at [default: end of the chip]
+        #[arg(short, long)]
+        end: Option<u16>,
+
+        /// How many non-blank addresses to list
+        #[arg(long, value_name = "N", default_value_t = 16)]
+        report_limit: usize,
+    },
+
+    /// Writes the same byte to a range of the EEPROM
+    Fill {
+        #[arg(long, default_value_t = 0xFF, value_parser = parse_number::<u8>)]
+        value: u8,
+
+        #[arg(short, long, default_value_t = 0x0000)]
+        start: u16,
+
+        /// Address to stop at [default: end of the chip]
+        #[arg(short, long)]
+        end: Option<u16>,
+
+        #[arg(long)]
+        no_verify: bool,
+    },
+
+    /// Checks whether the board runs compatible firmware that can handle --chip
+    CheckFirmware,
+
+    /// Prints the version of the firmware on the board
+    FirmwareVersion,
+
+    /// Prints the checksums the board would compute for each chunk of a file,
+    /// without connecting to it
+    Checksum {
+        filename: PathBuf,
+
+        /// Format of the file (binary, hex or srec)
+        #[arg(long, default_value = "binary")]
+        format: FileFormat,
+    },
+
+    /// Lists the serial ports the board could be connected to
+    ListPorts {
+        /// Print the ports as JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Flashes new firmware to the Arduino using avrdude
+    FirmwareUpdate {
+        /// Firmware image in Intel HEX format
+        firmware_hex: PathBuf,
+
+        /// avrdude executable to use instead of the one in $PATH
+        #[arg(long, value_name = "PATH")]
+        avrdude_path: Option<PathBuf>,
+    },
+
+    /// Runs the operation described by a JSON or TOML options file
+    RunConfig { config: PathBuf },
+
+    /// Prints a commented template for the defaults file
+    GenerateConfig,
 }
 
 /// A program to interact with AT28C EEPROM chips
 #[derive(Debug, Clone, Parser)]
 #[command(version, about, long_about = None)]
 struct Args {
-    /// Path to the port where the board is connected
-    #[arg(short, long, default_value = "/dev/ttyUSB0")]
-    port: String,
+    /// TOML file with defaults for the connection options [default:
+    /// ~/.config/eeprom-programmer/config.toml]
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// Path to the port where the board is connected, or tcp://HOST:PORT for a
+    /// port shared over the network. Detected automatically if only one
+    /// Arduino is plugged in.
+    #[arg(short, long)]
+    port: Option<String>,
+
+    /// Use /dev/ttyUSB0 instead of looking for the board when --port is not
+    /// given
+    #[arg(long)]
+    no_autodetect: bool,
 
     /// Baud rate for the connection
     #[arg(short, long, default_value_t = 115200)]
     baud_rate: u32,
 
+    /// Find the baud rate by trying common ones until the board answers
+    #[arg(long, conflicts_with = "baud_rate")]
+    auto_baud: bool,
+
+    /// Flow control for the serial port (none, hardware or software). Helps
+    /// converters that drop bytes at high baud rates.
+    #[arg(long, value_name = "MODE", default_value = "none")]
+    flow_control: FlowControlMode,
+
+    /// Byte order of 16-bit values sent to and from the board (be or le).
+    /// The stock firmware is big-endian.
+    #[arg(long, value_name = "ORDER", default_value = "be")]
+    byte_order: ByteOrder,
+
     /// Timeout (in milliseconds) for connecting to the Arduino
     #[arg(short, long, default_value_t = 10)]
     timeout: u64,
 
+    /// Timeout (in milliseconds) for reads once the Arduino is connected
+    #[arg(long, default_value_t = 500)]
+    operation_timeout: u64,
+
+    /// Timeout (in milliseconds) for the board to send the first chunk of a read
+    #[arg(long, value_name = "MS", default_value_t = DEFAULT_START_TIMEOUT_MS)]
+    read_timeout: u64,
+
+    /// Timeout (in milliseconds) for the board to ask for the first chunk of a write
+    #[arg(long, value_name = "MS", default_value_t = DEFAULT_START_TIMEOUT_MS)]
+    write_timeout: u64,
+
+    /// Timeout (in milliseconds) for the board to ask for the first chunk to verify
+    #[arg(long, value_name = "MS", default_value_t = DEFAULT_START_TIMEOUT_MS)]
+    verify_timeout: u64,
+
+    /// Bytes sent to the board per chunk when writing or verifying (1-251)
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_WRITE_CHUNK_SIZE)]
+    chunk_size: u8,
+
+    /// Times to re-send a chunk that reached the board corrupted before giving up
+    #[arg(long, value_name = "N", default_value_t = 3)]
+    max_retries: u8,
+
+    /// EEPROM model in the socket (at28c16, at28c64 or at28c256)
+    #[arg(long, default_value = "at28c256")]
+    chip: Chip,
+
+    /// Refuse to run unless the board's firmware version matches, e.g. ">=1.1, <2"
+    #[arg(long, value_name = "VERSION")]
+    require_version: Option<VersionReq>,
+
+    /// Align operations to blocks of this many bytes, padding writes with 0xFF
+    #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+    block_size: u16,
+
+    /// Timeout (in seconds) for the board to erase the EEPROM
+    #[arg(long, default_value_t = 30)]
+    erase_timeout: u64,
+
+    /// Print addresses in hexadecimal instead of decimal
+    #[arg(long)]
+    hex_addresses: bool,
+
+    /// Add this base address to all displayed addresses
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    address_shift: u16,
+
+    /// Refuse any operation touching an address above this one
+    #[arg(long, value_name = "ADDRESS")]
+    max_address: Option<u16>,
+
+    /// Wait for the board to send N Ready packets in a row before starting,
+    /// to make sure it is done resetting
+    #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    wait_for_ready_count: u32,
+
+    /// Skip up to N consecutive chunks that time out while reading, filling
+    /// them with 0xFE instead of aborting
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    timeout_recovery_reads: usize,
+
+    /// Normalize line endings in messages printed by the Arduino (crlf, lf or native)
+    #[arg(long, value_name = "MODE", default_value = "native")]
+    line_ending: LineEndingMode,
+
+    /// Enable options meant for testing the uploader itself
+    #[arg(long)]
+    debug: bool,
+
+    /// Pretend the board reported a mismatch while verifying (ADDRESS:EXPECTED:FOUND)
+    #[arg(long, value_name = "MISMATCH", requires = "debug", value_parser = parse_mismatch)]
+    inject_mismatch: Vec<ByteMismatch>,
+
+    /// Report all mismatches at once after verifying instead of as they come
+    #[arg(long)]
+    batch_mismatches: bool,
+
+    /// Show a full-screen terminal UI instead of plain output
+    #[cfg(feature = "tui")]
+    #[arg(long)]
+    tui: bool,
+
+    /// Number of recent progress updates used to compute the throughput
+    #[arg(long, value_name = "N", default_value_t = 32)]
+    progress_history: usize,
+
+    /// Don't use colors in the output
+    #[arg(long)]
+    no_color: bool,
+
+    /// Don't show progress bars
+    #[arg(long)]
+    no_progress: bool,
+
+    /// Only print results and errors
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// Length of the progress bar, in characters
+    #[arg(long, default_value_t = 20, value_parser = clap::value_parser!(u8).range(5..=200))]
+    bar_len: u8,
+
+    /// Also write messages printed by the Arduino to this file
+    #[arg(long, value_name = "PATH")]
+    echo_arduino_messages: Option<PathBuf>,
+
+    /// Warn when reading a single byte takes longer than this many milliseconds
+    #[arg(long, value_name = "MS")]
+    timeout_warn: Option<u64>,
+
+    /// Try to reopen the port this many times if it disconnects mid-operation.
+    /// The operation then restarts from the beginning, since the board resets
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    auto_reconnect: u32,
+
+    /// Time (in milliseconds) to wait before each reconnect attempt
+    #[arg(long, value_name = "MS", default_value_t = 1000)]
+    reconnect_delay: u64,
+
+    /// Record the whole protocol session to this file
+    #[arg(long, value_name = "PATH", conflicts_with = "replay_session")]
+    record_session: Option<PathBuf>,
+
+    /// Replay a recorded session instead of talking to the board, checking
+    /// that the uploader behaves exactly as it did during the recording
+    #[arg(long, value_name = "PATH")]
+    replay_session: Option<PathBuf>,
+
+    /// Log every value sent to or received from the board to stderr
+    #[arg(long)]
+    trace: bool,
+
+    /// Write per-chunk timing data to this CSV file
+    #[arg(long, value_name = "PATH")]
+    report_timing: Option<PathBuf>,
+
+    /// Shell command to run after the operation succeeds
+    #[arg(long, value_name = "CMD")]
+    on_success: Option<String>,
+
+    /// Shell command to run after the operation fails
+    #[arg(long, value_name = "CMD")]
+    on_failure: Option<String>,
+
     #[command(subcommand)]
     command: Command,
 }
 
 impl From<Args> for UserOptions {
     fn from(args: Args) -> Self {
-        Self {
-            command: match args.command {
-                Command::Read {
-                    out_file,
-                    start,
-                    end,
-                } => UserCommand::Read {
-                    out_filename: out_file,
-                    start,
-                    end,
-                },
-                Command::Write {
-                    filename,
-                    no_verify,
-                } => UserCommand::Write {
-                    in_filename: filename,
-                    verify: !no_verify,
-                },
-                Command::Verify { filename, fix } => UserCommand::Verify {
-                    in_filename: filename,
-                    fix,
-                },
+        let chip_end = args.chip.end_address();
+
+        let command = match args.command {
+            Command::Read {
+                out_file,
+                start,
+                end,
+                read_chunk_size,
+                write_back,
+                format,
+                record_size,
+                symbol_name,
+                prepend_bom,
+                srec_record_type,
+            } => UserCommand::Read {
+                out_filename: out_file,
+                start,
+                end: end.unwrap_or(chip_end),
+                chunk_size: read_chunk_size,
+                write_back,
+                format,
+                record_size,
+                symbol_name,
+                prepend_bom,
+                srec_record_type,
+            },
+            Command::Write {
+                filename,
+                no_verify,
+                verify_with_read,
+                write_once,
+                input_skip,
+                trim,
+                input_checksum,
+                no_fix,
+                only_data_pages,
+                write_order,
+                spot_check,
+                erase_before_write,
+                format,
+                offset,
+                start,
+                end,
+                dry_run,
+                verify_only,
+                page_write,
+                ..
+            } => UserCommand::Write {
+                in_filename: filename[0].clone(),
+                extra_files: filename
+                    .into_iter()
+                    .zip(offset.iter().copied())
+                    .skip(1)
+                    .map(|(path, offset)| InputSegment { path, offset })
+                    .collect(),
+                verify: !no_verify,
+                verify_with_read,
+                write_once,
+                skip: input_skip,
+                trim,
+                checksum: input_checksum,
+                fix: !no_fix,
+                only_data_pages,
+                order: write_order,
+                spot_checks: spot_check,
+                erase_before_write,
+                format,
+                offset: offset.first().copied().unwrap_or(0),
+                start,
+                end,
+                dry_run,
+                verify_only,
+                page_write,
             },
+            Command::Verify {
+                filename,
+                fix,
+                collect_mismatches,
+                report,
+                ..
+            } => UserCommand::Verify {
+                in_filename: filename,
+                fix,
+                collect_mismatches: collect_mismatches.map(NonZeroUsize::get),
+                report,
+            },
+            Command::BlankCheck {
+                start,
+                end,
+                report_limit,
+            } => UserCommand::BlankCheck {
+                start,
+                end: end.unwrap_or(chip_end),
+                report_limit,
+            },
+            Command::Fill {
+                value,
+                start,
+                end,
+                no_verify,
+            } => UserCommand::Fill {
+                value,
+                start,
+                end: end.unwrap_or(chip_end),
+                verify: !no_verify,
+            },
+            // The new firmware is checked once it has been flashed
+            Command::CheckFirmware | Command::FirmwareUpdate { .. } => UserCommand::CheckFirmware,
+            Command::FirmwareVersion => UserCommand::FirmwareVersion,
+            Command::RunConfig { .. } => unreachable!("options files are loaded separately"),
+            Command::ListPorts { .. } => unreachable!("listing ports does not need any options"),
+            Command::Checksum { .. } => unreachable!("checksums are computed offline"),
+            Command::GenerateConfig => unreachable!("the template does not need any options"),
+        };
+
+        Self::default()
+            .with_command(command)
+            .with_port(args.port.unwrap_or_else(|| serial::DEFAULT_PORT.to_owned()))
+            .with_hex_addresses(args.hex_addresses)
+            .with_address_shift(args.address_shift)
+            .with_batch_mismatches(args.batch_mismatches)
+            .with_max_address(args.max_address)
+            .with_timeout_recovery_reads(args.timeout_recovery_reads)
+            .with_wait_for_ready_count(args.wait_for_ready_count)
+            .with_line_ending(args.line_ending)
+            .with_chunk_size(args.chunk_size)
+            .with_max_retries(args.max_retries)
+            .with_chip(args.chip)
+            .with_required_version(args.require_version)
+            .with_block_size(args.block_size)
+            .with_injected_mismatches(args.inject_mismatch)
+            .with_operation_timeout(Duration::from_millis(args.operation_timeout))
+            .with_read_timeout(Duration::from_millis(args.read_timeout))
+            .with_write_timeout(Duration::from_millis(args.write_timeout))
+            .with_verify_timeout(Duration::from_millis(args.verify_timeout))
+            .with_erase_timeout(Duration::from_secs(args.erase_timeout))
+    }
+}
+
+fn parse_number<T: TryFrom<u32>>(s: &str) -> Result<T, String> {
+    let value = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|err| format!("invalid number '{}': {}", s, err))?;
+
+    T::try_from(value).map_err(|_| format!("{} is out of range", s))
+}
+
+fn parse_mismatch(s: &str) -> Result<ByteMismatch, String> {
+    let parts: Vec<&str> = s.split(':').collect();
+
+    let [address, expected, found] = parts[..] else {
+        return Err("expected ADDRESS:EXPECTED:FOUND".to_owned());
+    };
+
+    Ok(ByteMismatch {
+        address: parse_number(address)?,
+        expected: parse_number(expected)?,
+        found: parse_number(found)?,
+    })
+}
+
+fn parse_spot_check(s: &str) -> Result<SpotCheck, String> {
+    let (address, expected) = s
+        .split_once(':')
+        .ok_or_else(|| "expected ADDRESS:EXPECTED".to_owned())?;
+
+    Ok(SpotCheck {
+        address: parse_number(address)?,
+        expected: parse_number(expected)?,
+    })
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct TransferStats {
+    bytes_transferred: usize,
+    mismatches: usize,
+    warnings: usize,
+    retries: usize,
+}
+
+#[derive(Debug)]
+struct Context<'a> {
+    hex_addresses: bool,
+    address_shift: u16,
+    stats: &'a mut TransferStats,
+    bar_len: usize,
+    color: bool,
+    show_progress: bool,
+    quiet: bool,
+    /// Set when stdout is taken by the data being read.
+    messages_to_stderr: bool,
+    throughput: RollingThroughput,
+    arduino_log: Option<BufWriter<File>>,
+    write_back: Option<WriteBack<SerialPortIO>>,
+    session: Option<SessionObserver>,
+    reconnect_attempts: u32,
+    reconnect_delay: Duration,
+    /// Set once the user presses Ctrl+C.
+    interrupted: Arc<AtomicBool>,
+    #[cfg(feature = "tui")]
+    tui: Option<tui::Tui>,
+}
+
+fn render_progress_bar(done: usize, total: usize, bar_len: usize) -> String {
+    let filled = (done * bar_len) / total;
+    let empty = bar_len - filled;
+
+    format!(
+        "[{}{}] {:>3}%",
+        "#".repeat(filled),
+        ".".repeat(empty),
+        (done * 100) / total
+    )
+}
+
+fn handle_effect(ctx: &mut Context, effect: Effect) -> Result<(), Error> {
+    match effect {
+        Effect::Print(ref s) => {
+            if let Some(log) = &mut ctx.arduino_log {
+                writeln!(log, "[{}] {}", timestamp(), s)?;
+                log.flush()?;
+            }
+        }
+        Effect::Warning(_) | Effect::OperationWarning(_) => ctx.stats.warnings += 1,
+        Effect::WriteBackChunk(ref chunk) => {
+            if let Some(write_back) = &mut ctx.write_back {
+                write_back.write_chunk(chunk)?;
+            }
         }
+        Effect::WriteBackEnd => {
+            if let Some(write_back) = ctx.write_back.take() {
+                write_back.finish()?;
+            }
+        }
+        Effect::TransferStats {
+            bytes_transferred,
+            mismatches,
+        } => {
+            ctx.stats.bytes_transferred = bytes_transferred;
+            ctx.stats.mismatches = mismatches;
+        }
+        _ => {}
+    }
+
+    let hidden = match effect {
+        Effect::Progress { .. }
+        | Effect::VerifyProgress { .. }
+        | Effect::WriteProgress { .. }
+        | Effect::FixingByte { .. }
+        | Effect::Erasing { .. }
+        | Effect::ProgressEnd => !ctx.show_progress,
+        Effect::Print(_)
+        | Effect::PrintLn(_)
+        | Effect::Warning(_)
+        | Effect::OperationWarning(_) => ctx.quiet,
+        _ => false,
+    };
+
+    if hidden {
+        return Ok(());
+    }
+
+    #[cfg(feature = "tui")]
+    if let Some(tui) = &mut ctx.tui {
+        tui.render(&effect, ctx.stats)?;
+        return Ok(());
+    }
+
+    print_effect(ctx, effect)?;
+    Ok(())
+}
+
+/// Describes the current transfer rate and the time left, if known yet.
+fn rate_suffix(ctx: &mut Context, done: usize, total: usize) -> String {
+    let rate = ctx.throughput.update(done);
+
+    if rate <= 0.0 {
+        return String::new();
     }
+
+    format!(
+        ", {}, ETA {:.0}s",
+        throughput::format_rate(rate),
+        total.saturating_sub(done) as f64 / rate
+    )
 }
 
-const BAR_LEN: usize = 20;
+fn print_effect(ctx: &mut Context, effect: Effect) -> std::io::Result<()> {
+    let mut out: Box<dyn Write> = if ctx.messages_to_stderr {
+        Box::new(std::io::stderr())
+    } else {
+        Box::new(std::io::stdout())
+    };
 
-fn handle_effect(effect: Effect) -> std::io::Result<()> {
     match effect {
-        Effect::PrintLn(s) => println!("{}", s),
+        Effect::PrintLn(s) => writeln!(out, "{}", s)?,
         Effect::Print(s) => {
-            print!("{}", s);
-            std::io::stdout().flush()?;
+            write!(out, "{}", s)?;
+            out.flush()?;
         }
         Effect::Progress { done, total } => {
-            let filled = (done * BAR_LEN) / total;
-            let empty = BAR_LEN - filled;
-
-            print!(
-                "\rProgress: [{}{}] {}%",
-                "#".repeat(filled),
-                ".".repeat(empty),
-                (done * 100) / total
-            );
-            std::io::stdout().flush()?;
+            write!(
+                out,
+                "\rProgress: {}{}",
+                render_progress_bar(done, total, ctx.bar_len),
+                rate_suffix(ctx, done, total)
+            )?;
+            out.flush()?;
         }
         Effect::VerifyProgress {
             done,
             total,
             mismatches,
         } => {
-            let filled = (done * BAR_LEN) / total;
-            let empty = BAR_LEN - filled;
-
-            print!(
-                "\rProgress: [{}{}] {}%, mismatches: {}",
-                "#".repeat(filled),
-                ".".repeat(empty),
-                (done * 100) / total,
-                mismatches
-            );
-            std::io::stdout().flush()?;
+            write!(
+                out,
+                "\rProgress: {}, mismatches: {}{}",
+                render_progress_bar(done, total, ctx.bar_len),
+                mismatches,
+                rate_suffix(ctx, done, total)
+            )?;
+            out.flush()?;
+        }
+        Effect::WriteProgress {
+            written,
+            total,
+            current_chunk,
+            total_chunks,
+            ..
+        } => {
+            write!(
+                out,
+                "\rWriting: {} ({}/{} bytes, chunk {}/{}){}",
+                render_progress_bar(written, total, ctx.bar_len),
+                written,
+                total,
+                current_chunk,
+                total_chunks,
+                rate_suffix(ctx, written, total)
+            )?;
+            out.flush()?;
+        }
+        Effect::FixingByte {
+            current,
+            total,
+            address,
+            value,
+        } => {
+            write!(
+                out,
+                "\rFixing: {} byte {}/{} at {} = 0x{:02X}",
+                render_progress_bar(current, total, ctx.bar_len),
+                current,
+                total,
+                format_addr(
+                    u32::from(address) + u32::from(ctx.address_shift),
+                    ctx.hex_addresses
+                ),
+                value
+            )?;
+            out.flush()?;
+        }
+        Effect::Erasing { elapsed } => {
+            const SPINNER: [char; 4] = ['|', '/', '-', '\\'];
+            let frame = SPINNER[(elapsed.as_millis() / 250) as usize % SPINNER.len()];
+
+            write!(out, "\rErasing... {} ({}s)", frame, elapsed.as_secs())?;
+            out.flush()?;
+        }
+        Effect::ProgressEnd => writeln!(out)?,
+        Effect::MismatchReport { mismatches } => {
+            writeln!(out, "{:<8} {:<8} {:<8}", "Address", "Expected", "Found")?;
+
+            for mismatch in mismatches {
+                writeln!(
+                    out,
+                    "{:<8} 0x{:02X}     0x{:02X}",
+                    format_addr(
+                        u32::from(mismatch.address) + u32::from(ctx.address_shift),
+                        ctx.hex_addresses
+                    ),
+                    mismatch.expected,
+                    mismatch.found
+                )?;
+            }
+        }
+        Effect::Warning(s) => {
+            writeln!(out)?;
+            writeln!(out, "Warning: {}", s)?;
+        }
+        Effect::OperationWarning(s) => {
+            writeln!(out)?;
+
+            if ctx.color {
+                eprintln!("\x1b[33m[WARNING] {}\x1b[0m", s);
+            } else {
+                eprintln!("[WARNING] {}", s);
+            }
+        }
+        Effect::Alert(s) => {
+            if ctx.color {
+                writeln!(out, "\x1b[1;31m{}\x1b[0m", s)?;
+            } else {
+                writeln!(out, "{}", s)?;
+            }
+        }
+        Effect::WriteSummary {
+            written,
+            verified,
+            fixed,
+        } => writeln!(
+            out,
+            "Wrote {} bytes, verified {} and fixed {}.",
+            written, verified, fixed
+        )?,
+        Effect::DryRunSummary {
+            file_size,
+            checksum,
+        } => {
+            writeln!(out, "Dry run, nothing was written.")?;
+            writeln!(out, "  Size:     {} bytes", file_size)?;
+            writeln!(out, "  Checksum: 0x{:04X}", checksum)?;
+        }
+        Effect::TransferSummary {
+            operation,
+            bytes,
+            duration_ms,
+            checksum,
+            retries,
+        } => {
+            let action = match operation {
+                OperationKind::Read => "Read",
+                OperationKind::Write => "Wrote",
+                OperationKind::Verify => "Verified",
+            };
+            let secs = duration_ms as f64 / 1000.0;
+
+            write!(out, "{} {} bytes in {:.2}s", action, bytes, secs)?;
+
+            if duration_ms > 0 {
+                write!(out, " ({})", throughput::format_rate(bytes as f64 / secs))?;
+            }
+
+            writeln!(out, ", checksum 0x{:04X}, {} retries.", checksum, retries)?;
         }
-        Effect::ProgressEnd => println!(),
+        Effect::WriteBackChunk(_) | Effect::WriteBackEnd | Effect::TransferStats { .. } => {}
     }
 
     Ok(())
 }
 
-fn run(args: Args) -> Result<(), Error> {
-    println!("Opening serial port...");
+fn list_ports(json: bool) -> Result<(), Error> {
+    let ports = ports::list_ports().map_err(std::io::Error::from)?;
+
+    if json {
+        let json = serde_json::to_string_pretty(&ports).map_err(std::io::Error::from)?;
+        println!("{}", json);
+    } else if ports.is_empty() {
+        println!("No serial ports found.");
+    } else {
+        for line in ports::format_table(&ports) {
+            println!("{}", line);
+        }
+    }
+
+    Ok(())
+}
 
-    let mut port = SerialPortIO::new(
-        &args.port,
-        args.baud_rate,
-        Duration::from_millis(args.timeout),
+/// Prints the checksum of each chunk of the file, split as when writing it
+/// in chunks of the largest size, followed by the checksum of the whole file.
+fn print_checksums(filename: &Path, format: FileFormat) -> Result<(), Error> {
+    let data = format.load(filename)?;
+    let chunk_size = usize::from(protocol::CHUNK_MAX_SIZE);
+
+    println!("{:<8} {:<6} Checksum", "Offset", "Length");
+
+    for (i, chunk) in data.chunks(chunk_size).enumerate() {
+        println!(
+            "0x{:04X}   {:<6} 0x{:04X}",
+            i * chunk_size,
+            chunk.len(),
+            protocol::calculate_checksum(chunk)
+        );
+    }
+
+    println!();
+    println!(
+        "Total: {} bytes, checksum 0x{:04X}",
+        data.len(),
+        protocol::calculate_checksum(&data)
+    );
+
+    Ok(())
+}
+
+fn run(mut args: Args, stats: &mut TransferStats) -> Result<(), Error> {
+    if let Command::ListPorts { json } = args.command {
+        return list_ports(json);
+    }
+
+    if let Command::GenerateConfig = args.command {
+        print!("{}", CONFIG_TEMPLATE);
+        return Ok(());
+    }
+
+    if let Command::Checksum {
+        ref filename,
+        format,
+    } = args.command
+    {
+        return print_checksums(filename, format);
+    }
+
+    // Replays and options files never use the port given on the command line
+    let detect_port = args.port.is_none()
+        && !args.no_autodetect
+        && args.replay_session.is_none()
+        && !matches!(args.command, Command::RunConfig { .. });
+
+    if detect_port {
+        args.port = Some(serial::resolve_port(None)?);
+    }
+
+    let mut baud_rate = args.baud_rate;
+    let auto_baud = args.auto_baud;
+    let flow_control = args.flow_control;
+    let byte_order = args.byte_order;
+    let timeout = Duration::from_millis(args.timeout);
+    let slow_read_threshold = args.timeout_warn.map(Duration::from_millis);
+
+    let bar_len = args.bar_len.into();
+    let color = !args.no_color;
+    let show_progress = !args.no_progress;
+    let quiet = args.quiet;
+    #[cfg(feature = "tui")]
+    let use_tui = args.tui;
+    let progress_history = args.progress_history;
+    let reconnect_attempts = args.auto_reconnect;
+    let reconnect_delay = Duration::from_millis(args.reconnect_delay);
+    let arduino_log = args
+        .echo_arduino_messages
+        .as_ref()
+        .map(File::create)
+        .transpose()?
+        .map(BufWriter::new);
+
+    let record_session = args.record_session.clone();
+    let replay_session = args.replay_session.clone();
+    let report_timing = args.report_timing.clone();
+    let trace = args.trace;
+
+    let (write_cache_path, force) = match args.command {
+        Command::Write {
+            ref write_cache,
+            force,
+            ..
+        } => (write_cache.clone(), force),
+        _ => (None, false),
+    };
+
+    let chip_count = match args.command {
+        Command::Write { count, .. } => count,
+        _ => 1,
+    };
+
+    let watch = match args.command {
+        Command::Verify {
+            watch: Some(interval_ms),
+            ..
+        } => Some(Duration::from_millis(interval_ms)),
+        _ => None,
+    };
+
+    let firmware_update = match args.command {
+        Command::FirmwareUpdate {
+            ref firmware_hex,
+            ref avrdude_path,
+        } => Some((firmware_hex.clone(), avrdude_path.clone())),
+        _ => None,
+    };
+
+    let user_opts = match args.command {
+        Command::RunConfig { ref config } => UserOptions::from_file(config).map_err(Box::new)?,
+        _ => UserOptions::from(args),
+    };
+    user_opts.validate()?;
+
+    let mut ctx = Context {
+        hex_addresses: user_opts.hex_addresses,
+        address_shift: user_opts.address_shift,
+        stats,
+        bar_len,
+        throughput: RollingThroughput::new(progress_history),
+        show_progress,
+        quiet,
+        messages_to_stderr: user_opts.command.dumps_to_stdout(),
+        color,
+        arduino_log,
+        write_back: None,
+        session: None,
+        reconnect_attempts,
+        reconnect_delay,
+        interrupted: watch_for_interrupt()?,
+        #[cfg(feature = "tui")]
+        tui: None,
+    };
+
+    if detect_port {
+        handle_effect(
+            &mut ctx,
+            Effect::PrintLn(format!("Found the board at {}", user_opts.port)),
+        )?;
+    }
+
+    let mut write_cache = None;
+
+    if let (
+        Some(cache_path),
+        UserCommand::Write {
+            in_filename,
+            verify,
+            verify_with_read,
+            start,
+            dry_run,
+            verify_only,
+            ..
+        },
+    ) = (write_cache_path, &user_opts.command)
+    {
+        let mut cache = WriteCache::load(&cache_path)?;
+        // The write itself reports how the image was built
+        let image = user_opts.write_image(&mut |_| {})?.unwrap_or_default();
+        let entry = WriteCacheEntry::new(in_filename, &image, user_opts.chip, (*start).into());
+
+        if !force && cache.contains(&entry) {
+            handle_effect(
+                &mut ctx,
+                Effect::PrintLn(
+                    "Chip already programmed with this image (cached). Use --force to re-write."
+                        .to_owned(),
+                ),
+            )?;
+            return Ok(());
+        }
+
+        if !dry_run && !verify_only {
+            // Whatever the cache said about these addresses stops being true
+            // as soon as the write starts, even if it fails halfway
+            cache.invalidate(&entry);
+            cache.save(&cache_path)?;
+        }
+
+        // Only verified writes are worth remembering
+        if (*verify || *verify_with_read) && !dry_run {
+            write_cache = Some((cache, cache_path, entry));
+        }
+    }
+
+    if let Some(replay_session) = replay_session {
+        let events = load_session(&replay_session)?;
+        let mut port = ReplaySerialIO::new(&events).with_byte_order(byte_order);
+        ctx.session = Some(SessionObserver::replay(&events));
+
+        handle_effect(
+            &mut ctx,
+            Effect::PrintLn(format!("Replaying session from {:?}...", replay_session)),
+        )?;
+        drive(&mut port, &mut ctx, &user_opts)?;
+
+        if let Some(session) = ctx.session.take() {
+            session.finish()?;
+        }
+
+        println!("Replay matches the recorded session.");
+        return Ok(());
+    }
+
+    if let Some((firmware_hex, avrdude_path)) = firmware_update {
+        handle_effect(
+            &mut ctx,
+            Effect::PrintLn(format!("Flashing firmware from {:?}...", firmware_hex)),
+        )?;
+        firmware_update::flash(&user_opts.port, &firmware_hex, avrdude_path.as_deref())?;
+        handle_effect(
+            &mut ctx,
+            Effect::PrintLn(
+                "Firmware flashed. Waiting for the board to come back up...".to_owned(),
+            ),
+        )?;
+    }
+
+    handle_effect(
+        &mut ctx,
+        Effect::PrintLn("Opening serial port...".to_owned()),
     )?;
+    // Ports shared over TCP have no baud rate of their own
+    if auto_baud && !user_opts.port.starts_with("tcp://") {
+        baud_rate = serial::find_baud_rate(&user_opts.port, flow_control)?;
+        eprintln!(
+            "The board answered at {} baud. Pass --baud-rate {} to skip probing next time.",
+            baud_rate, baud_rate
+        );
+    }
+
+    let mut port: Box<dyn SerialIO> = match user_opts.port.strip_prefix("tcp://") {
+        Some(addr) => Box::new(TcpSerialIO::new(addr)?.with_byte_order(byte_order)),
+        None => Box::new(
+            SerialPortIO::new(&user_opts.port, baud_rate, timeout, flow_control)?
+                .with_byte_order(byte_order)
+                .with_slow_read_threshold(slow_read_threshold),
+        ),
+    };
+
+    if let UserCommand::Read {
+        write_back: Some(ref write_back_port),
+        ..
+    } = user_opts.command
+    {
+        handle_effect(
+            &mut ctx,
+            Effect::PrintLn("Opening write-back serial port...".to_owned()),
+        )?;
+        let write_back_port = SerialPortIO::new(write_back_port, baud_rate, timeout, flow_control)?
+            .with_byte_order(byte_order);
+        ctx.write_back = Some(WriteBack::start(write_back_port)?);
+    }
+
+    if trace {
+        port = Box::new(TracingSerialIO::new(port));
+    }
+
+    if let Some(report_timing) = report_timing {
+        port = Box::new(TimingSerialIO::new(port, &report_timing)?);
+    }
+
+    if let Some(record_session) = record_session {
+        let recorder = Rc::new(RefCell::new(SessionRecorder::create(&record_session)?));
+        ctx.session = Some(SessionObserver::Record(recorder.clone()));
+
+        port = Box::new(RecordingSerialIO::new(port, recorder));
+    }
+
+    #[cfg(feature = "tui")]
+    if use_tui {
+        ctx.tui = Some(tui::Tui::new(baud_rate, progress_history)?);
+    }
+
+    if let Some(interval) = watch {
+        return watch_verify(&mut port, &mut ctx, &user_opts, interval);
+    }
+
+    if chip_count > 1 {
+        return write_batch(&mut port, &mut ctx, &user_opts, chip_count);
+    }
+
+    drive(&mut port, &mut ctx, &user_opts)?;
+
+    if let Some((mut cache, cache_path, entry)) = write_cache
+        && ctx.stats.mismatches == 0
+    {
+        cache.insert(entry);
+        cache.save(&cache_path)?;
+    }
+
+    Ok(())
+}
+
+/// Seconds since the Unix epoch, with milliseconds.
+fn timestamp() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
 
-    let user_opts = UserOptions::from(args);
-    let mut state = State::Idle;
+    format!("{}.{:03}", now.as_secs(), now.subsec_millis())
+}
+
+/// How often the pause between watch passes checks for Ctrl+C.
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(100);
 
-    loop {
-        let packet = protocol::read_packet(&mut port)?;
-        let (new_state, effects) = state.transition(packet, &mut port, &user_opts)?;
+/// Verifies the chip over and over, `interval` apart, until Ctrl+C is
+/// pressed. The board is ready for the next pass as soon as one ends, so the
+/// port stays open throughout.
+fn watch_verify(
+    port: &mut impl SerialIO,
+    ctx: &mut Context,
+    user_opts: &UserOptions,
+    interval: Duration,
+) -> Result<(), Error> {
+    let mut previous = None;
 
-        for effect in effects {
-            handle_effect(effect)?;
+    for pass in 1.. {
+        match drive(port, ctx, user_opts) {
+            Ok(()) => {}
+            Err(Error::Interrupted) => return Ok(()),
+            Err(err) => return Err(err),
         }
 
-        if let State::Finished(result) = new_state {
-            return result;
+        let mismatches = ctx.stats.mismatches;
+
+        handle_effect(
+            ctx,
+            Effect::PrintLn(format!(
+                "[{}] Pass {}: {} mismatches.",
+                timestamp(),
+                pass,
+                mismatches
+            )),
+        )?;
+
+        if let Some(previous) = previous
+            && mismatches > previous
+        {
+            handle_effect(
+                ctx,
+                Effect::Alert(format!(
+                    "Mismatches went up from {} to {}!",
+                    previous, mismatches
+                )),
+            )?;
         }
 
-        state = new_state;
+        previous = Some(mismatches);
+
+        let start = Instant::now();
+
+        while start.elapsed() < interval {
+            if ctx.interrupted.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+
+            thread::sleep(WATCH_POLL_INTERVAL.min(interval.saturating_sub(start.elapsed())));
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes the same data to `count` chips one after another, waiting for the
+/// user to swap chips in between. The board sends Ready again once a write
+/// is done, so the port stays open throughout.
+fn write_batch(
+    port: &mut impl SerialIO,
+    ctx: &mut Context,
+    user_opts: &UserOptions,
+    count: u32,
+) -> Result<(), Error> {
+    let mut succeeded = 0;
+    let mut failed = 0;
+
+    for chip in 1..=count {
+        let result = drive(port, ctx, user_opts);
+
+        match result {
+            Ok(()) => succeeded += 1,
+            // Past these, the board cannot be talked to anymore
+            Err(err @ (Error::Interrupted | Error::PortDisconnected)) => return Err(err),
+            Err(err) => {
+                failed += 1;
+                handle_effect(
+                    ctx,
+                    Effect::Warning(format!("Chip {} failed: {}", chip, err)),
+                )?;
+            }
+        }
+
+        handle_effect(
+            ctx,
+            Effect::PrintLn(format!(
+                "Chip {}/{} done ({} succeeded, {} failed).",
+                chip, count, succeeded, failed
+            )),
+        )?;
+
+        if chip == count {
+            break;
+        }
+
+        handle_effect(
+            ctx,
+            Effect::PrintLn("Insert the next chip and press Enter...".to_owned()),
+        )?;
+        std::io::stdin().read_line(&mut String::new())?;
+
+        if ctx.interrupted.load(Ordering::Relaxed) {
+            return Err(Error::Interrupted);
+        }
+    }
+
+    if failed > 0 {
+        return Err(Error::BatchWriteFailed { failed, count });
+    }
+
+    Ok(())
+}
+
+fn drive(
+    port: &mut impl SerialIO,
+    ctx: &mut Context,
+    user_opts: &UserOptions,
+) -> Result<(), Error> {
+    let mut driver = StateDriver::new(State::Idle, port, user_opts);
+    let mut kind = StateKind::Idle;
+    let mut aborted = false;
+
+    while let Some(step) = driver.next() {
+        // The board takes a while to boot or erase, so waiting for it is never slow
+        let waiting = matches!(kind, StateKind::Idle | StateKind::Erasing);
+
+        let (new_kind, effects) = match step {
+            Ok(step) => step,
+            Err(Error::PortDisconnected) if ctx.reconnect_attempts > 0 => {
+                reconnect(driver.port_mut(), ctx)?;
+                handle_effect(
+                    ctx,
+                    Effect::PrintLn(
+                        "Reconnected, restarting the operation from the beginning...".to_owned(),
+                    ),
+                )?;
+
+                driver.restart();
+                kind = StateKind::Idle;
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        kind = new_kind;
+
+        if let Some(session) = &mut ctx.session {
+            session.observe(SessionData::Transition {
+                state: format!("{:?}", kind),
+            })?;
+
+            for effect in &effects {
+                session.observe(SessionData::Effect {
+                    effect: format!("{:?}", effect),
+                })?;
+            }
+        }
+
+        let slow_reads = driver
+            .port_mut()
+            .take_slow_reads()
+            .into_iter()
+            .filter(|_| !waiting)
+            .map(|elapsed| Effect::Warning(format!("Slow read: {}ms", elapsed.as_millis())));
+
+        for effect in effects.into_iter().chain(slow_reads) {
+            handle_effect(ctx, effect)?;
+        }
+
+        if !aborted && ctx.interrupted.load(Ordering::Relaxed) {
+            aborted = true;
+
+            let mut effects = vec![];
+            driver.abort(&mut |effect| effects.push(effect))?;
+
+            for effect in effects {
+                handle_effect(ctx, effect)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Makes Ctrl+C set the returned flag instead of killing the process, so that
+/// the operation can be stopped cleanly. Pressing it again kills the process.
+fn watch_for_interrupt() -> std::io::Result<Arc<AtomicBool>> {
+    let interrupted = Arc::new(AtomicBool::new(false));
+
+    // Registered first, so that it only fires once the flag is already set
+    signal_hook::flag::register_conditional_shutdown(SIGINT, 1, interrupted.clone())?;
+    signal_hook::flag::register(SIGINT, interrupted.clone())?;
+
+    Ok(interrupted)
+}
+
+fn reconnect(port: &mut impl SerialIO, ctx: &mut Context) -> Result<(), Error> {
+    for attempt in 1..=ctx.reconnect_attempts {
+        let message = format!(
+            "Port disconnected, reconnecting (attempt {}/{})...",
+            attempt, ctx.reconnect_attempts
+        );
+        handle_effect(ctx, Effect::PrintLn(message))?;
+
+        thread::sleep(ctx.reconnect_delay);
+        ctx.stats.retries += 1;
+
+        match port.reconnect() {
+            Ok(()) => return Ok(()),
+            Err(err) => handle_effect(ctx, Effect::Warning(format!("Reconnect failed: {}", err)))?,
+        }
+    }
+
+    Err(Error::PortDisconnected)
+}
+
+fn run_hook(cmd: &str, stats: &TransferStats, elapsed: Duration) -> std::io::Result<()> {
+    let status = process::Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .env(
+            "EEPROM_BYTES_TRANSFERRED",
+            stats.bytes_transferred.to_string(),
+        )
+        .env("EEPROM_ELAPSED_MS", elapsed.as_millis().to_string())
+        .env("EEPROM_MISMATCH_COUNT", stats.mismatches.to_string())
+        .env("EEPROM_WARNING_COUNT", stats.warnings.to_string())
+        .status()?;
+
+    if !status.success() {
+        eprintln!("Warning: hook command exited with {}", status);
+    }
+
+    Ok(())
+}
+
+/// Fills in the arguments that were not given on the command line from the
+/// defaults file, if there is one.
+fn apply_config(args: &mut Args, matches: &ArgMatches) -> Result<(), ConfigError> {
+    let config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::load_default()?,
+    };
+
+    let from_cli = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+
+    if args.port.is_none() {
+        args.port = config.port;
+    }
+
+    if let Some(baud_rate) = config.baud_rate
+        && !from_cli("baud_rate")
+    {
+        args.baud_rate = baud_rate;
+    }
+
+    if let Some(timeout) = config.timeout
+        && !from_cli("timeout")
+    {
+        args.timeout = timeout;
+    }
+
+    if let Some(chunk_size) = config.chunk_size
+        && !from_cli("chunk_size")
+    {
+        args.chunk_size = chunk_size;
+    }
+
+    if let Some(chip) = config.chip
+        && !from_cli("chip")
+    {
+        args.chip = chip;
     }
+
+    Ok(())
 }
 
 fn main() -> anyhow::Result<()> {
-    let args = Args::parse();
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    apply_config(&mut args, &matches)?;
+
+    if let Command::Write {
+        ref filename,
+        ref offset,
+        ..
+    } = args.command
+        && (filename.len() > 1 || offset.len() > 1)
+        && offset.len() != filename.len()
+    {
+        Args::command()
+            .error(
+                ErrorKind::WrongNumberOfValues,
+                "each file needs its own --offset when writing several",
+            )
+            .exit();
+    }
+
+    let mut filter = EnvFilter::from_default_env();
+
+    if args.trace {
+        // The protocol's spans name the packets being read
+        filter = filter
+            .add_directive(format!("{}=trace", trace::TARGET).parse().unwrap())
+            .add_directive("eeprom_programmer::protocol=info".parse().unwrap());
+    }
+
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .init();
+
+    let on_success = args.on_success.clone();
+    let on_failure = args.on_failure.clone();
+
+    let start = Instant::now();
+    let mut stats = TransferStats::default();
+    let result = run(args, &mut stats);
 
-    Ok(run(args)?)
+    let hook = if result.is_ok() {
+        on_success
+    } else {
+        on_failure
+    };
+
+    if let Some(cmd) = hook {
+        run_hook(&cmd, &stats, start.elapsed())?;
+    }
+
+    Ok(result?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress_bar_at_half() {
+        assert_eq!(render_progress_bar(50, 100, 10), "[#####.....]  50%");
+    }
+
+    #[test]
+    fn progress_bar_at_start_and_end() {
+        assert_eq!(render_progress_bar(0, 100, 10), "[..........]   0%");
+        assert_eq!(render_progress_bar(100, 100, 10), "[##########] 100%");
+    }
+
+    #[test]
+    fn progress_bar_rounds_down() {
+        assert_eq!(render_progress_bar(2, 3, 4), "[##..]  66%");
+    }
 }
@@ -0,0 +1,84 @@
+use std::{fs::File, io::Write, path::Path};
+
+use serde::Serialize;
+
+use crate::{core::ByteMismatch, display};
+
+/// Bytes of the reference file shown on each side of a mismatch.
+const CONTEXT_LEN: usize = 4;
+
+#[derive(Debug, Serialize)]
+struct ReportEntry {
+    address: String,
+    expected: u8,
+    found: u8,
+    before: Vec<u8>,
+    after: Vec<u8>,
+}
+
+impl ReportEntry {
+    /// Describes `mismatch`, taking its context from `data`, which starts at
+    /// EEPROM address `base`.
+    fn new(mismatch: &ByteMismatch, data: &[u8], base: usize) -> Self {
+        let offset = usize::from(mismatch.address).saturating_sub(base);
+        let before_start = offset.saturating_sub(CONTEXT_LEN).min(data.len());
+        let after_end = (offset + 1 + CONTEXT_LEN).min(data.len());
+
+        Self {
+            address: format!("0x{:04X}", mismatch.address),
+            expected: mismatch.expected,
+            found: mismatch.found,
+            before: data[before_start..offset.min(data.len())].to_vec(),
+            after: data[(offset + 1).min(data.len())..after_end].to_vec(),
+        }
+    }
+}
+
+/// Writes a report of `mismatches` to `path`, as JSON if its extension is
+/// `.json` and as a table otherwise.
+pub fn write(
+    path: &Path,
+    mismatches: &[ByteMismatch],
+    data: &[u8],
+    base: usize,
+) -> std::io::Result<()> {
+    let entries: Vec<_> = mismatches
+        .iter()
+        .map(|mismatch| ReportEntry::new(mismatch, data, base))
+        .collect();
+
+    let mut out = File::create(path)?;
+
+    if path.extension().is_some_and(|ext| ext == "json") {
+        serde_json::to_writer_pretty(&mut out, &entries).map_err(std::io::Error::from)?;
+        return writeln!(out);
+    }
+
+    writeln!(out, "{} mismatches", entries.len())?;
+
+    if entries.is_empty() {
+        return Ok(());
+    }
+
+    writeln!(out)?;
+    writeln!(
+        out,
+        "{:<8} {:<8} {:<8} Context (expected)",
+        "Address", "Expected", "Found"
+    )?;
+
+    for entry in &entries {
+        writeln!(
+            out,
+            "{:<8} 0x{:02X}     0x{:02X}     {:>11} [{:02X}] {}",
+            entry.address,
+            entry.expected,
+            entry.found,
+            display::hex_bytes(&entry.before),
+            entry.expected,
+            display::hex_bytes(&entry.after)
+        )?;
+    }
+
+    Ok(())
+}
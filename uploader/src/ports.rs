@@ -0,0 +1,123 @@
+use serde::Serialize;
+use serialport::{SerialPortInfo, SerialPortType};
+
+/// USB vendor IDs found on Arduino boards and their common clones.
+const ARDUINO_VIDS: [u16; 5] = [
+    0x2341, // Arduino LLC
+    0x2A03, // Arduino SRL
+    0x1A86, // QinHeng, whose CH340 is used by most clones
+    0x0403, // FTDI, found on older boards
+    0x10C4, // Silicon Labs, whose CP210x is used by some clones
+];
+
+/// What is known about a serial port the board could be connected to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PortDescription {
+    pub name: String,
+    pub kind: &'static str,
+    pub vid: Option<u16>,
+    pub pid: Option<u16>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial_number: Option<String>,
+    pub likely_arduino: bool,
+}
+
+impl From<SerialPortInfo> for PortDescription {
+    fn from(port: SerialPortInfo) -> Self {
+        let mut description = Self {
+            name: port.port_name,
+            kind: "unknown",
+            vid: None,
+            pid: None,
+            manufacturer: None,
+            product: None,
+            serial_number: None,
+            likely_arduino: false,
+        };
+
+        match port.port_type {
+            SerialPortType::UsbPort(usb) => {
+                description.kind = "USB";
+                description.vid = Some(usb.vid);
+                description.pid = Some(usb.pid);
+                description.manufacturer = usb.manufacturer;
+                description.product = usb.product;
+                description.serial_number = usb.serial_number;
+                description.likely_arduino = ARDUINO_VIDS.contains(&usb.vid);
+            }
+            SerialPortType::PciPort => description.kind = "PCI",
+            SerialPortType::BluetoothPort => description.kind = "Bluetooth",
+            SerialPortType::Unknown => {}
+        }
+
+        description
+    }
+}
+
+pub fn list_ports() -> serialport::Result<Vec<PortDescription>> {
+    let ports = serialport::available_ports()?;
+
+    Ok(ports.into_iter().map(PortDescription::from).collect())
+}
+
+/// Lays out `ports` as a table, one line per port after the header.
+pub fn format_table(ports: &[PortDescription]) -> Vec<String> {
+    let rows: Vec<[String; 6]> = ports
+        .iter()
+        .map(|port| {
+            let mut name = port.name.clone();
+
+            if port.likely_arduino {
+                name.push_str(" [Arduino?]");
+            }
+
+            let usb_id = match (port.vid, port.pid) {
+                (Some(vid), Some(pid)) => format!("{:04x}:{:04x}", vid, pid),
+                _ => "-".to_owned(),
+            };
+
+            let or_dash = |field: &Option<String>| field.clone().unwrap_or_else(|| "-".to_owned());
+
+            [
+                name,
+                port.kind.to_owned(),
+                usb_id,
+                or_dash(&port.manufacturer),
+                or_dash(&port.product),
+                or_dash(&port.serial_number),
+            ]
+        })
+        .collect();
+
+    let header = [
+        "Port",
+        "Type",
+        "VID:PID",
+        "Manufacturer",
+        "Product",
+        "Serial",
+    ]
+    .map(String::from);
+
+    let mut widths = header.clone().map(|column| column.len());
+
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    std::iter::once(&header)
+        .chain(&rows)
+        .map(|row| {
+            row.iter()
+                .zip(widths)
+                .map(|(cell, width)| format!("{:<width$}", cell))
+                .collect::<Vec<_>>()
+                .join("  ")
+                .trim_end()
+                .to_owned()
+        })
+        .collect()
+}
@@ -6,17 +6,65 @@ use crate::serial::SerialIO;
 
 #[derive(Debug, From, Display, Error)]
 pub enum ProtocolError {
-    #[display("Received a packet with invalid opcode: {_0:02X}")]
-    InvalidPacketOpcode(#[error(not(source))] u8),
+    #[display(
+        "Unknown opcode 0x{opcode:02X}{} (followed by {raw_context:02X?})",
+        previous.map(|previous| format!(" (after '{}')", packet_name(previous))).unwrap_or_default()
+    )]
+    InvalidPacketOpcode {
+        opcode: u8,
+        previous: Option<u8>,
+        raw_context: [u8; 8],
+    },
 
     #[display("A received string packet does not contain valid UTF-8")]
     InvalidUtf8(#[from] FromUtf8Error),
 
+    #[display("I/O error: {_0}")]
+    IO(#[from] std::io::Error),
+
+    #[display("Expected {expected} bytes from the board, but only got {got}")]
+    #[from(skip)]
+    ShortRead { expected: usize, got: usize },
+
+    /// Errors from the port that are not plain I/O errors.
     #[display("Unknown error: {_0}")]
-    Unknown(#[from] anyhow::Error),
+    #[from(skip)]
+    Unknown(anyhow::Error),
+}
+
+impl From<anyhow::Error> for ProtocolError {
+    fn from(err: anyhow::Error) -> Self {
+        let err = match err.downcast::<Self>() {
+            Ok(err) => return err,
+            Err(err) => err,
+        };
+
+        match err.downcast::<std::io::Error>() {
+            Ok(err) => Self::IO(err),
+            Err(err) => Self::Unknown(err),
+        }
+    }
 }
 
-#[derive(Debug, Clone, Display)]
+impl ProtocolError {
+    /// Records the opcode of the packet received before the failing one.
+    pub fn after(self, previous: Option<u8>) -> Self {
+        match self {
+            Self::InvalidPacketOpcode {
+                opcode,
+                raw_context,
+                ..
+            } => Self::InvalidPacketOpcode {
+                opcode,
+                previous,
+                raw_context,
+            },
+            err => err,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Display, PartialEq, Eq)]
 pub enum Packet {
     #[display("Ready")]
     Ready,
@@ -38,22 +86,118 @@ pub enum Packet {
     },
     #[display("ByteRequest")]
     ByteRequest,
+    #[display("Error")]
+    Error { code: u8, message: String },
+    #[display("Ack")]
+    Ack,
+    #[display("FirmwareVersion")]
+    FirmwareVersion { major: u8, minor: u8, patch: u8 },
+    #[display("VersionOk")]
+    VersionOk,
+    #[display("VersionMismatch")]
+    VersionMismatch { firmware_version: u16 },
+    #[display("FirmwareLimits")]
+    FirmwareLimits {
+        max_chunk_size: u8,
+        max_page_size: u8,
+    },
+}
+
+impl Packet {
+    pub fn opcode(&self) -> u8 {
+        match self {
+            Self::Ready => 0x00,
+            Self::Print(_) => 0x01,
+            Self::Chunk { .. } => 0x02,
+            Self::ReadEnd => 0x03,
+            Self::ChunkRequest => 0x04,
+            Self::InvalidChecksum { .. } => 0x05,
+            Self::ByteMismatch { .. } => 0x06,
+            Self::ByteRequest => 0x07,
+            Self::Error { .. } => 0x08,
+            Self::Ack => 0x09,
+            Self::FirmwareVersion { .. } => 0x0A,
+            Self::VersionOk => 0x0B,
+            Self::VersionMismatch { .. } => 0x0C,
+            Self::FirmwareLimits { .. } => 0x0D,
+        }
+    }
+}
+
+/// Name of the packet sent by the board with the given opcode.
+pub fn packet_name(opcode: u8) -> &'static str {
+    match opcode {
+        0x00 => "Ready",
+        0x01 => "Print",
+        0x02 => "Chunk",
+        0x03 => "ReadEnd",
+        0x04 => "ChunkRequest",
+        0x05 => "InvalidChecksum",
+        0x06 => "ByteMismatch",
+        0x07 => "ByteRequest",
+        0x08 => "Error",
+        0x09 => "Ack",
+        0x0A => "FirmwareVersion",
+        0x0B => "VersionOk",
+        0x0C => "VersionMismatch",
+        0x0D => "FirmwareLimits",
+        _ => "Unknown",
+    }
+}
+
+/// Name of the command sent by the host with the given opcode.
+pub fn host_command_name(opcode: u8) -> &'static str {
+    match opcode {
+        0x00 => "Read",
+        0x01 => "Write",
+        0x02 => "Verify",
+        0x03 => "Erase",
+        0x09 => "FirmwareVersion",
+        0x0A => "FirmwareLimits",
+        NOP => "Nop",
+        NEGOTIATE => "Negotiate",
+        _ => "Unknown",
+    }
 }
 
+/// Computes the Fletcher-16-style checksum that the board attaches to chunks,
+/// with both running sums wrapping at 256 instead of 255. The second sum is
+/// the high byte, as in the firmware's `calculate_checksum`.
 pub fn calculate_checksum(data: &[u8]) -> u16 {
-    let mut sum_1 = 0_u8;
-    let mut sum_2 = 0_u8;
+    let mut checksum = RunningChecksum::default();
+    checksum.update(data);
+    checksum.value()
+}
 
-    for &n in data {
-        sum_1 = sum_1.wrapping_add(n);
-        sum_2 = sum_2.wrapping_add(sum_1);
+/// [`calculate_checksum`] of data that arrives a piece at a time.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RunningChecksum {
+    sum_1: u8,
+    sum_2: u8,
+}
+
+impl RunningChecksum {
+    pub fn update(&mut self, data: &[u8]) {
+        for &n in data {
+            self.sum_1 = self.sum_1.wrapping_add(n);
+            self.sum_2 = self.sum_2.wrapping_add(self.sum_1);
+        }
     }
 
-    u16::from_ne_bytes([sum_1, sum_2])
+    pub fn value(self) -> u16 {
+        u16::from_le_bytes([self.sum_1, self.sum_2])
+    }
 }
 
+#[tracing::instrument(
+    skip_all,
+    fields(opcode = tracing::field::Empty, packet = tracing::field::Empty)
+)]
 pub fn read_packet(port: &mut dyn SerialIO) -> Result<Packet, ProtocolError> {
     let opcode = port.read_u8()?;
+    tracing::Span::current()
+        .record("opcode", opcode)
+        .record("packet", packet_name(opcode));
 
     match opcode {
         0x00 => Ok(Packet::Ready),
@@ -87,24 +231,181 @@ pub fn read_packet(port: &mut dyn SerialIO) -> Result<Packet, ProtocolError> {
             })
         }
         0x07 => Ok(Packet::ByteRequest),
-        _ => Err(ProtocolError::InvalidPacketOpcode(opcode)),
+        0x08 => {
+            let code = port.read_u8()?;
+            let len = port.read_u8()?.into();
+            let bytes = port.read_n(len)?;
+            let message = String::from_utf8(bytes)?;
+            Ok(Packet::Error { code, message })
+        }
+        0x09 => Ok(Packet::Ack),
+        0x0A => {
+            let major = port.read_u8()?;
+            let minor = port.read_u8()?;
+            let patch = port.read_u8()?;
+            Ok(Packet::FirmwareVersion {
+                major,
+                minor,
+                patch,
+            })
+        }
+        0x0B => Ok(Packet::VersionOk),
+        0x0C => {
+            let firmware_version = port.read_u16()?;
+            Ok(Packet::VersionMismatch { firmware_version })
+        }
+        0x0D => {
+            let max_chunk_size = port.read_u8()?;
+            let max_page_size = port.read_u8()?;
+            Ok(Packet::FirmwareLimits {
+                max_chunk_size,
+                max_page_size,
+            })
+        }
+        _ => {
+            let mut raw_context = [0; 8];
+            let available = port.read_available(raw_context.len())?;
+            raw_context[..available.len()].copy_from_slice(&available);
+
+            Err(ProtocolError::InvalidPacketOpcode {
+                opcode,
+                previous: None,
+                raw_context,
+            })
+        }
     }
 }
 
+/// Version of the protocol spoken by the uploader, checked against the
+/// firmware's while negotiating. Version 2 added the end address and page
+/// size to the erase command, and version 3 the firmware limits query.
+pub const PROTOCOL_VERSION: u16 = 0x0003;
+
+/// Command that the board ignores, so that it sends Ready again right away.
+pub const NOP: u8 = 0x0F;
+
+/// Command that starts protocol version negotiation. The board acknowledges
+/// it, then reads the host's protocol version. The version is only sent after
+/// the Ack because firmware that does not know this command would take its
+/// first byte, 0x00, for a Read command.
+pub const NEGOTIATE: u8 = 0xFE;
+
+/// Sent instead of a chunk size to make the board skip over a chunk without
+/// writing it. Followed by the number of bytes to skip.
+const SKIP_PAGE: u8 = 0xFE;
+
+/// Sent instead of a chunk size to move the board's write address. Followed
+/// by the new address.
+const SET_ADDRESS: u8 = 0xFD;
+
+/// Sent instead of a chunk size to make the board stop writing or verifying
+/// right away, without verifying or fixing anything. Answered with an ack.
+const ABORT: u8 = 0xFC;
+
+/// Largest chunk of data that can be sent to the board at once. Any larger
+/// size would be mistaken for one of the markers above.
+pub const CHUNK_MAX_SIZE: u8 = ABORT - 1;
+
+/// Bits of the first byte following the write command.
+pub const WRITE_VERIFY: u8 = 0x01;
+pub const WRITE_PAGES: u8 = 0x02;
+
+pub fn send_command(port: &mut impl SerialIO, opcode: u8) -> anyhow::Result<()> {
+    tracing::debug!(command = host_command_name(opcode), "sending command");
+    port.write_u8(opcode)
+}
+
+/// Has the board erase addresses `[0, end)`, writing `page_size` bytes per
+/// write cycle.
+pub fn send_erase(port: &mut impl SerialIO, end: u16, page_size: u8) -> anyhow::Result<()> {
+    send_command(port, 0x03)?;
+    port.write_u16(end)?;
+    port.write_u8(page_size)
+}
+
+pub fn send_set_address(port: &mut impl SerialIO, address: u16) -> anyhow::Result<()> {
+    port.write_u8(SET_ADDRESS)?;
+    port.write_u16(address)
+}
+
+pub fn send_abort(port: &mut impl SerialIO) -> anyhow::Result<()> {
+    port.write_u8(ABORT)
+}
+
+#[tracing::instrument(
+    skip_all,
+    fields(chunk_size = tracing::field::Empty, current_byte = *current_byte, total = data.len())
+)]
+/// Sends the next chunk of `data`, returning whether it was skipped because
+/// `skip_blank` is set and the chunk is all 0xFF.
 pub fn send_data_chunk(
     port: &mut impl SerialIO,
     data: &[u8],
     current_byte: &mut usize,
-) -> anyhow::Result<()> {
-    const CHUNK_MAX_SIZE: usize = 16;
+    chunk_size: usize,
+    skip_blank: bool,
+) -> Result<bool, ProtocolError> {
     let data_left = &data[*current_byte..];
 
-    let chunk = &data_left[..CHUNK_MAX_SIZE.min(data_left.len())];
+    let chunk = &data_left[..chunk_size.min(data_left.len())];
+    tracing::Span::current().record("chunk_size", chunk.len());
+
+    *current_byte += chunk.len();
+
+    if skip_blank && chunk.iter().all(|&b| b == 0xFF) {
+        port.write_u8(SKIP_PAGE)?;
+        port.write_u8(chunk.len().try_into().unwrap())?;
+        return Ok(true);
+    }
 
     port.write_u8(chunk.len().try_into().unwrap())?;
     port.write_u16(calculate_checksum(chunk))?;
     port.write_n(chunk)?;
-    *current_byte += chunk.len();
 
-    Ok(())
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksum_matches_known_vectors() {
+        let chunk: Vec<u8> = (0..64).collect();
+
+        assert_eq!(calculate_checksum(b"abcde"), 0xC3EF);
+        assert_eq!(calculate_checksum(b"abcdef"), 0x1855);
+        assert_eq!(calculate_checksum(&chunk), 0xA0E0);
+    }
+
+    #[test]
+    fn checksum_sums_wrap_at_256() {
+        assert_eq!(calculate_checksum(&[0xFF, 0xFF]), 0xFDFE);
+        assert_eq!(calculate_checksum(&[0xFF; 64]), 0xE0C0);
+    }
+
+    #[test]
+    fn running_checksum_in_pieces_matches_whole() {
+        let data: Vec<u8> = (0..=255).cycle().take(300).collect();
+
+        for split in [0, 1, 63, 64, 150, 299, 300] {
+            let mut checksum = RunningChecksum::default();
+            checksum.update(&data[..split]);
+            checksum.update(&data[split..]);
+            assert_eq!(
+                checksum.value(),
+                calculate_checksum(&data),
+                "split at {split}"
+            );
+        }
+    }
+
+    #[test]
+    fn running_checksum_byte_by_byte_matches_whole() {
+        let mut checksum = RunningChecksum::default();
+        for &byte in b"abcdef" {
+            checksum.update(&[byte]);
+        }
+        assert_eq!(checksum.value(), 0x1855);
+    }
 }
@@ -1,50 +1,318 @@
-use std::time::Duration;
+use std::{
+    io::{ErrorKind, Read, Write},
+    net::TcpStream,
+    str::FromStr,
+    thread,
+    time::{Duration, Instant},
+};
 
-use serialport::SerialPort;
+use anyhow::anyhow;
+use serialport::{FlowControl, SerialPort};
+
+use crate::{
+    error::Error,
+    ports,
+    protocol::{self, Packet, ProtocolError},
+};
+
+/// Port used when auto-detection is turned off and no port is given.
+pub const DEFAULT_PORT: &str = "/dev/ttyUSB0";
+
+/// Baud rates tried by [`find_baud_rate`], in order.
+const PROBE_BAUD_RATES: [u32; 6] = [9600, 19200, 38400, 57600, 115200, 230400];
+
+/// How long the board gets to say it is ready at each probed baud rate.
+const PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How long to sleep between checks for incoming data, so that waiting on
+/// the board does not keep a core busy.
+const POLL_INTERVAL: Duration = Duration::from_micros(100);
+
+/// Order of the two bytes of every `u16` exchanged with the board. The stock
+/// firmware sends the most significant byte first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ByteOrder {
+    #[default]
+    BigEndian,
+    LittleEndian,
+}
+
+impl ByteOrder {
+    pub fn decode(self, bytes: [u8; 2]) -> u16 {
+        match self {
+            Self::BigEndian => u16::from_be_bytes(bytes),
+            Self::LittleEndian => u16::from_le_bytes(bytes),
+        }
+    }
+
+    pub fn encode(self, value: u16) -> [u8; 2] {
+        match self {
+            Self::BigEndian => value.to_be_bytes(),
+            Self::LittleEndian => value.to_le_bytes(),
+        }
+    }
+}
+
+impl FromStr for ByteOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "be" => Ok(Self::BigEndian),
+            "le" => Ok(Self::LittleEndian),
+            _ => Err(format!("unknown byte order '{}' (expected be or le)", s)),
+        }
+    }
+}
 
 pub trait SerialIO {
     fn read_u8(&mut self) -> anyhow::Result<u8>;
     fn read_u16(&mut self) -> anyhow::Result<u16>;
     fn read_n(&mut self, n: usize) -> anyhow::Result<Vec<u8>>;
+    fn read_available(&mut self, max: usize) -> anyhow::Result<Vec<u8>>;
 
     fn write_u8(&mut self, value: u8) -> anyhow::Result<()>;
     fn write_u16(&mut self, value: u16) -> anyhow::Result<()>;
     fn write_n(&mut self, data: &[u8]) -> anyhow::Result<()>;
+
+    /// Sends whatever the OS or driver is still holding back of what was
+    /// written, which some USB-serial drivers only do once asked to.
+    fn flush(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn set_timeout(&mut self, _timeout: Duration) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Returns the durations of the slow reads recorded since the last call.
+    fn take_slow_reads(&mut self) -> Vec<Duration> {
+        vec![]
+    }
+
+    /// Re-opens the underlying connection with the same settings.
+    fn reconnect(&mut self) -> anyhow::Result<()> {
+        Err(anyhow!("This connection cannot be reopened"))
+    }
+
+    /// Byte order used by [`read_u16`](Self::read_u16) and
+    /// [`write_u16`](Self::write_u16).
+    fn byte_order(&self) -> ByteOrder {
+        ByteOrder::BigEndian
+    }
+}
+
+impl<T: SerialIO + ?Sized> SerialIO for Box<T> {
+    fn read_u8(&mut self) -> anyhow::Result<u8> {
+        (**self).read_u8()
+    }
+
+    fn read_u16(&mut self) -> anyhow::Result<u16> {
+        (**self).read_u16()
+    }
+
+    fn read_n(&mut self, n: usize) -> anyhow::Result<Vec<u8>> {
+        (**self).read_n(n)
+    }
+
+    fn read_available(&mut self, max: usize) -> anyhow::Result<Vec<u8>> {
+        (**self).read_available(max)
+    }
+
+    fn write_u8(&mut self, value: u8) -> anyhow::Result<()> {
+        (**self).write_u8(value)
+    }
+
+    fn write_u16(&mut self, value: u16) -> anyhow::Result<()> {
+        (**self).write_u16(value)
+    }
+
+    fn write_n(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        (**self).write_n(data)
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        (**self).flush()
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> anyhow::Result<()> {
+        (**self).set_timeout(timeout)
+    }
+
+    fn take_slow_reads(&mut self) -> Vec<Duration> {
+        (**self).take_slow_reads()
+    }
+
+    fn reconnect(&mut self) -> anyhow::Result<()> {
+        (**self).reconnect()
+    }
+
+    fn byte_order(&self) -> ByteOrder {
+        (**self).byte_order()
+    }
+}
+
+/// How the serial port keeps the board from being sent more than it can
+/// take in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FlowControlMode {
+    #[default]
+    None,
+    /// RTS/CTS lines.
+    Hardware,
+    /// XON/XOFF characters.
+    Software,
+}
+
+impl From<FlowControlMode> for FlowControl {
+    fn from(mode: FlowControlMode) -> Self {
+        match mode {
+            FlowControlMode::None => Self::None,
+            FlowControlMode::Hardware => Self::Hardware,
+            FlowControlMode::Software => Self::Software,
+        }
+    }
+}
+
+impl FromStr for FlowControlMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "hardware" => Ok(Self::Hardware),
+            "software" => Ok(Self::Software),
+            _ => Err(format!(
+                "unknown flow control '{}' (expected none, hardware or software)",
+                s
+            )),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct SerialPortIO {
     port: Box<dyn SerialPort>,
+    path: String,
+    baud_rate: u32,
+    flow_control: FlowControlMode,
+    byte_order: ByteOrder,
+    slow_read_threshold: Option<Duration>,
+    slow_reads: Vec<Duration>,
+    read_deadline: Option<Duration>,
 }
 
 impl SerialPortIO {
-    pub fn new(path: &str, baud_rate: u32, timeout: Duration) -> anyhow::Result<Self> {
-        let port = serialport::new(path, baud_rate).timeout(timeout).open()?;
+    pub fn new(
+        path: &str,
+        baud_rate: u32,
+        timeout: Duration,
+        flow_control: FlowControlMode,
+    ) -> anyhow::Result<Self> {
+        let port = serialport::new(path, baud_rate)
+            .timeout(timeout)
+            .flow_control(flow_control.into())
+            .open()?;
 
-        Ok(SerialPortIO { port })
+        Ok(SerialPortIO {
+            port,
+            path: path.to_owned(),
+            baud_rate,
+            flow_control,
+            byte_order: ByteOrder::default(),
+            slow_read_threshold: None,
+            slow_reads: vec![],
+            read_deadline: None,
+        })
+    }
+
+    pub fn with_byte_order(self, byte_order: ByteOrder) -> Self {
+        Self { byte_order, ..self }
+    }
+
+    /// Records every byte read that takes longer than `threshold`.
+    pub fn with_slow_read_threshold(self, threshold: Option<Duration>) -> Self {
+        Self {
+            slow_read_threshold: threshold,
+            ..self
+        }
+    }
+
+    /// Waits until at least `n` bytes can be read, giving up once the read
+    /// deadline passes.
+    fn wait_for(&mut self, n: u32) -> anyhow::Result<()> {
+        let start = Instant::now();
+
+        while self.port.bytes_to_read()? < n {
+            if self
+                .read_deadline
+                .is_some_and(|deadline| start.elapsed() > deadline)
+            {
+                return Err(std::io::Error::from(std::io::ErrorKind::TimedOut).into());
+            }
+
+            thread::sleep(POLL_INTERVAL);
+        }
+
+        Ok(())
     }
 }
 
 impl SerialIO for SerialPortIO {
     fn read_u8(&mut self) -> anyhow::Result<u8> {
+        let start = Instant::now();
         let mut buf = [0];
 
-        while self.port.bytes_to_read()? == 0 {}
+        self.wait_for(1)?;
 
         self.port.read_exact(&mut buf)?;
+
+        if let Some(threshold) = self.slow_read_threshold {
+            let elapsed = start.elapsed();
+
+            if elapsed > threshold {
+                self.slow_reads.push(elapsed);
+            }
+        }
+
         Ok(buf[0])
     }
 
     fn read_u16(&mut self) -> anyhow::Result<u16> {
-        while self.port.bytes_to_read()? < 2 {}
+        self.wait_for(2)?;
 
         let mut buf = [0; 2];
         self.port.read_exact(&mut buf)?;
-        Ok(u16::from_be_bytes(buf))
+        Ok(self.byte_order.decode(buf))
     }
 
     fn read_n(&mut self, n: usize) -> anyhow::Result<Vec<u8>> {
-        while self.port.bytes_to_read()? < n.try_into().unwrap() {}
+        self.wait_for(n.try_into().unwrap())?;
+
+        // Some backends overstate how many bytes are waiting, so a read can
+        // still come up short once the port's timeout expires
+        let mut buf = vec![0; n];
+        let mut got = 0;
+
+        while got < n {
+            match self.port.read(&mut buf[got..]) {
+                Ok(0) => break,
+                Ok(read) => got += read,
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) if err.kind() == ErrorKind::TimedOut => break,
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        if got != n {
+            return Err(ProtocolError::ShortRead { expected: n, got }.into());
+        }
+
+        Ok(buf)
+    }
+
+    fn read_available(&mut self, max: usize) -> anyhow::Result<Vec<u8>> {
+        let n = (self.port.bytes_to_read()? as usize).min(max);
 
         let mut buf = vec![0; n];
         self.port.read_exact(&mut buf)?;
@@ -57,7 +325,7 @@ impl SerialIO for SerialPortIO {
     }
 
     fn write_u16(&mut self, value: u16) -> anyhow::Result<()> {
-        self.port.write_all(&value.to_be_bytes())?;
+        self.port.write_all(&self.byte_order.encode(value))?;
         Ok(())
     }
 
@@ -65,4 +333,268 @@ impl SerialIO for SerialPortIO {
         self.port.write_all(data)?;
         Ok(())
     }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.port.flush()?;
+        Ok(())
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> anyhow::Result<()> {
+        self.port.set_timeout(timeout)?;
+        self.read_deadline = Some(timeout);
+        Ok(())
+    }
+
+    fn take_slow_reads(&mut self) -> Vec<Duration> {
+        std::mem::take(&mut self.slow_reads)
+    }
+
+    fn reconnect(&mut self) -> anyhow::Result<()> {
+        let timeout = self.port.timeout();
+
+        self.port = serialport::new(&self.path, self.baud_rate)
+            .timeout(timeout)
+            .flow_control(self.flow_control.into())
+            .open()?;
+
+        Ok(())
+    }
+
+    fn byte_order(&self) -> ByteOrder {
+        self.byte_order
+    }
+}
+
+/// Talks to a board whose serial port is exposed over TCP, e.g. by ser2net.
+#[derive(Debug)]
+pub struct TcpSerialIO {
+    stream: TcpStream,
+    addr: String,
+    byte_order: ByteOrder,
+}
+
+impl TcpSerialIO {
+    /// Connects to `addr` (`host:port`). Like [`SerialPortIO`], reads block
+    /// until a timeout is set, so that booting boards are waited for.
+    pub fn new(addr: &str) -> anyhow::Result<Self> {
+        Ok(Self {
+            stream: Self::connect(addr)?,
+            addr: addr.to_owned(),
+            byte_order: ByteOrder::default(),
+        })
+    }
+
+    pub fn with_byte_order(self, byte_order: ByteOrder) -> Self {
+        Self { byte_order, ..self }
+    }
+
+    fn connect(addr: &str) -> std::io::Result<TcpStream> {
+        let stream = TcpStream::connect(addr)?;
+        // Packets are small, so waiting to batch them only adds latency
+        stream.set_nodelay(true)?;
+        Ok(stream)
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> anyhow::Result<()> {
+        self.stream
+            .read_exact(buf)
+            .map_err(|err| match err.kind() {
+                // What a timed out read looks like on Unix
+                ErrorKind::WouldBlock => std::io::Error::from(ErrorKind::TimedOut),
+                // The board is gone, not just slow
+                ErrorKind::UnexpectedEof => std::io::Error::from(ErrorKind::NotConnected),
+                _ => err,
+            })?;
+
+        Ok(())
+    }
+}
+
+impl SerialIO for TcpSerialIO {
+    fn read_u8(&mut self) -> anyhow::Result<u8> {
+        let mut buf = [0];
+        self.read_exact(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    fn read_u16(&mut self) -> anyhow::Result<u16> {
+        let mut buf = [0; 2];
+        self.read_exact(&mut buf)?;
+        Ok(self.byte_order.decode(buf))
+    }
+
+    fn read_n(&mut self, n: usize) -> anyhow::Result<Vec<u8>> {
+        let mut buf = vec![0; n];
+        self.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn read_available(&mut self, max: usize) -> anyhow::Result<Vec<u8>> {
+        let mut buf = vec![0; max];
+
+        self.stream.set_nonblocking(true)?;
+        let result = self.stream.read(&mut buf);
+        self.stream.set_nonblocking(false)?;
+
+        let n = match result {
+            Ok(n) => n,
+            Err(err) if err.kind() == ErrorKind::WouldBlock => 0,
+            Err(err) => return Err(err.into()),
+        };
+
+        buf.truncate(n);
+        Ok(buf)
+    }
+
+    fn write_u8(&mut self, value: u8) -> anyhow::Result<()> {
+        self.stream.write_all(&[value])?;
+        Ok(())
+    }
+
+    fn write_u16(&mut self, value: u16) -> anyhow::Result<()> {
+        self.stream.write_all(&self.byte_order.encode(value))?;
+        Ok(())
+    }
+
+    fn write_n(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        self.stream.write_all(data)?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.stream.flush()?;
+        Ok(())
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> anyhow::Result<()> {
+        self.stream.set_read_timeout(Some(timeout))?;
+        Ok(())
+    }
+
+    fn reconnect(&mut self) -> anyhow::Result<()> {
+        let timeout = self.stream.read_timeout()?;
+
+        self.stream = Self::connect(&self.addr)?;
+        self.stream.set_read_timeout(timeout)?;
+
+        Ok(())
+    }
+
+    fn byte_order(&self) -> ByteOrder {
+        self.byte_order
+    }
+}
+
+/// Discards whatever the board sends until it has been silent for `quiet`,
+/// returning how many bytes were thrown away.
+pub fn drain_input(port: &mut impl SerialIO, quiet: Duration) -> anyhow::Result<usize> {
+    let mut drained = 0;
+    let mut last_data = Instant::now();
+
+    while last_data.elapsed() < quiet {
+        let bytes = port.read_available(64)?;
+
+        if bytes.is_empty() {
+            thread::sleep(POLL_INTERVAL);
+        } else {
+            drained += bytes.len();
+            last_data = Instant::now();
+        }
+    }
+
+    Ok(drained)
+}
+
+/// Returns `port` if given, and otherwise the one USB serial port that looks
+/// like an Arduino.
+pub fn resolve_port(port: Option<String>) -> Result<String, Error> {
+    if let Some(port) = port {
+        return Ok(port);
+    }
+
+    let mut candidates: Vec<_> = ports::list_ports()
+        .map_err(std::io::Error::from)?
+        .into_iter()
+        .filter(|port| port.likely_arduino)
+        .map(|port| port.name)
+        .collect();
+
+    match candidates.len() {
+        0 => Err(Error::NoBoardFound),
+        1 => Ok(candidates.remove(0)),
+        _ => Err(Error::MultipleBoardsFound { ports: candidates }),
+    }
+}
+
+/// Finds the baud rate the board at `path` talks at, by opening the port at
+/// each common rate until the board sends Ready.
+pub fn find_baud_rate(path: &str, flow_control: FlowControlMode) -> Result<u32, Error> {
+    for baud_rate in PROBE_BAUD_RATES {
+        let mut port = SerialPortIO::new(path, baud_rate, PROBE_TIMEOUT, flow_control)?;
+        port.set_timeout(PROBE_TIMEOUT)?;
+
+        if let Ok(Packet::Ready) = protocol::read_packet(&mut port) {
+            return Ok(baud_rate);
+        }
+    }
+
+    Err(Error::BaudRateNotFound {
+        tried: PROBE_BAUD_RATES.to_vec(),
+    })
+}
+
+/// Whether `err` was caused by the serial port going away, e.g. because the
+/// board was unplugged or re-enumerated.
+pub fn is_disconnect(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        if let Some(err) = cause.downcast_ref::<serialport::Error>() {
+            return err.kind() == serialport::ErrorKind::NoDevice;
+        }
+
+        cause
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(is_io_disconnect)
+    })
+}
+
+/// Like [`is_disconnect`], for a plain I/O error.
+pub fn is_io_disconnect(err: &std::io::Error) -> bool {
+    matches!(
+        err.kind(),
+        std::io::ErrorKind::BrokenPipe
+            | std::io::ErrorKind::NotConnected
+            | std::io::ErrorKind::NotFound
+    )
+}
+
+/// Whether `err` was caused by the board not answering in time.
+pub fn is_timeout(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|err| err.kind() == std::io::ErrorKind::TimedOut)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockSerialIO;
+
+    #[test]
+    fn drain_input_discards_pending_bytes() {
+        let mut port = MockSerialIO::new(&[0x02, 4, 0x14, 0x0A, 1, 2, 3, 4]);
+
+        let drained = drain_input(&mut port, Duration::from_millis(1)).unwrap();
+
+        assert_eq!(drained, 8);
+        assert_eq!(port.unread(), 0);
+        assert!(port.writes.is_empty());
+    }
+
+    #[test]
+    fn drain_input_on_silent_port() {
+        let mut port = MockSerialIO::new(&[]);
+        assert_eq!(drain_input(&mut port, Duration::from_millis(1)).unwrap(), 0);
+    }
 }
@@ -0,0 +1,319 @@
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::Error,
+    serial::{ByteOrder, SerialIO},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SessionData {
+    Received { bytes: Vec<u8> },
+    Sent { bytes: Vec<u8> },
+    Effect { effect: String },
+    Transition { state: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionEvent {
+    pub time_ns: u64,
+    #[serde(flatten)]
+    pub data: SessionData,
+}
+
+/// Writes every event of a session to a newline-delimited JSON file.
+#[derive(Debug)]
+pub struct SessionRecorder {
+    start: Instant,
+    out: BufWriter<File>,
+}
+
+impl SessionRecorder {
+    pub fn create(path: &Path) -> std::io::Result<Self> {
+        Ok(Self {
+            start: Instant::now(),
+            out: BufWriter::new(File::create(path)?),
+        })
+    }
+
+    pub fn record(&mut self, data: SessionData) -> Result<(), Error> {
+        let event = SessionEvent {
+            time_ns: self
+                .start
+                .elapsed()
+                .as_nanos()
+                .try_into()
+                .unwrap_or(u64::MAX),
+            data,
+        };
+
+        serde_json::to_writer(&mut self.out, &event)?;
+        writeln!(self.out)?;
+        self.out.flush()?;
+
+        Ok(())
+    }
+}
+
+pub fn load_session(path: &Path) -> Result<Vec<SessionEvent>, Error> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut events = vec![];
+
+    for line in reader.lines() {
+        let line = line?;
+
+        if !line.trim().is_empty() {
+            events.push(serde_json::from_str(&line)?);
+        }
+    }
+
+    Ok(events)
+}
+
+/// Wraps a [`SerialIO`], recording all traffic going through it.
+#[derive(Debug)]
+pub struct RecordingSerialIO<IO: SerialIO> {
+    inner: IO,
+    recorder: Rc<RefCell<SessionRecorder>>,
+}
+
+impl<IO: SerialIO> RecordingSerialIO<IO> {
+    pub fn new(inner: IO, recorder: Rc<RefCell<SessionRecorder>>) -> Self {
+        Self { inner, recorder }
+    }
+
+    fn received(&self, bytes: &[u8]) -> anyhow::Result<()> {
+        self.recorder.borrow_mut().record(SessionData::Received {
+            bytes: bytes.to_vec(),
+        })?;
+        Ok(())
+    }
+
+    fn sent(&self, bytes: &[u8]) -> anyhow::Result<()> {
+        self.recorder.borrow_mut().record(SessionData::Sent {
+            bytes: bytes.to_vec(),
+        })?;
+        Ok(())
+    }
+}
+
+impl<IO: SerialIO> SerialIO for RecordingSerialIO<IO> {
+    fn read_u8(&mut self) -> anyhow::Result<u8> {
+        let value = self.inner.read_u8()?;
+        self.received(&[value])?;
+        Ok(value)
+    }
+
+    fn read_u16(&mut self) -> anyhow::Result<u16> {
+        let value = self.inner.read_u16()?;
+        self.received(&self.inner.byte_order().encode(value))?;
+        Ok(value)
+    }
+
+    fn read_n(&mut self, n: usize) -> anyhow::Result<Vec<u8>> {
+        let data = self.inner.read_n(n)?;
+        self.received(&data)?;
+        Ok(data)
+    }
+
+    fn read_available(&mut self, max: usize) -> anyhow::Result<Vec<u8>> {
+        let data = self.inner.read_available(max)?;
+        self.received(&data)?;
+        Ok(data)
+    }
+
+    fn write_u8(&mut self, value: u8) -> anyhow::Result<()> {
+        self.inner.write_u8(value)?;
+        self.sent(&[value])
+    }
+
+    fn write_u16(&mut self, value: u16) -> anyhow::Result<()> {
+        self.inner.write_u16(value)?;
+        self.sent(&self.inner.byte_order().encode(value))
+    }
+
+    fn write_n(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        self.inner.write_n(data)?;
+        self.sent(data)
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.inner.flush()
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> anyhow::Result<()> {
+        self.inner.set_timeout(timeout)
+    }
+
+    fn take_slow_reads(&mut self) -> Vec<Duration> {
+        self.inner.take_slow_reads()
+    }
+
+    fn reconnect(&mut self) -> anyhow::Result<()> {
+        self.inner.reconnect()
+    }
+
+    fn byte_order(&self) -> ByteOrder {
+        self.inner.byte_order()
+    }
+}
+
+/// Plays back the bytes received in a recorded session, checking that the
+/// host sends exactly the same bytes as it did during the recording.
+#[derive(Debug)]
+pub struct ReplaySerialIO {
+    received: VecDeque<u8>,
+    sent: VecDeque<u8>,
+    byte_order: ByteOrder,
+}
+
+impl ReplaySerialIO {
+    pub fn new(events: &[SessionEvent]) -> Self {
+        let mut received = VecDeque::new();
+        let mut sent = VecDeque::new();
+
+        for event in events {
+            match &event.data {
+                SessionData::Received { bytes } => received.extend(bytes),
+                SessionData::Sent { bytes } => sent.extend(bytes),
+                _ => {}
+            }
+        }
+
+        Self {
+            received,
+            sent,
+            byte_order: ByteOrder::default(),
+        }
+    }
+
+    /// Decodes `u16`s with `byte_order`, which must match the recording's.
+    pub fn with_byte_order(self, byte_order: ByteOrder) -> Self {
+        Self { byte_order, ..self }
+    }
+
+    fn take(&mut self, n: usize) -> anyhow::Result<Vec<u8>> {
+        if self.received.len() < n {
+            return Err(anyhow!("Recorded session ended unexpectedly"));
+        }
+
+        Ok(self.received.drain(..n).collect())
+    }
+
+    fn expect_sent(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        for &byte in data {
+            match self.sent.pop_front() {
+                Some(expected) if expected == byte => {}
+                Some(expected) => {
+                    return Err(anyhow!(
+                        "Host sent 0x{:02X}, but 0x{:02X} was recorded",
+                        byte,
+                        expected
+                    ));
+                }
+                None => {
+                    return Err(anyhow!(
+                        "Host sent 0x{:02X} after the recorded session ended",
+                        byte
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl SerialIO for ReplaySerialIO {
+    fn read_u8(&mut self) -> anyhow::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn read_u16(&mut self) -> anyhow::Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(self.byte_order.decode([bytes[0], bytes[1]]))
+    }
+
+    fn read_n(&mut self, n: usize) -> anyhow::Result<Vec<u8>> {
+        self.take(n)
+    }
+
+    fn read_available(&mut self, max: usize) -> anyhow::Result<Vec<u8>> {
+        self.take(max.min(self.received.len()))
+    }
+
+    fn write_u8(&mut self, value: u8) -> anyhow::Result<()> {
+        self.expect_sent(&[value])
+    }
+
+    fn write_u16(&mut self, value: u16) -> anyhow::Result<()> {
+        self.expect_sent(&self.byte_order.encode(value))
+    }
+
+    fn write_n(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        self.expect_sent(data)
+    }
+
+    fn byte_order(&self) -> ByteOrder {
+        self.byte_order
+    }
+}
+
+/// Either records the effects and transitions of a session, or checks them
+/// against a previous recording.
+#[derive(Debug)]
+pub enum SessionObserver {
+    Record(Rc<RefCell<SessionRecorder>>),
+    Replay(VecDeque<SessionData>),
+}
+
+impl SessionObserver {
+    pub fn replay(events: &[SessionEvent]) -> Self {
+        Self::Replay(
+            events
+                .iter()
+                .filter(|event| {
+                    matches!(
+                        event.data,
+                        SessionData::Effect { .. } | SessionData::Transition { .. }
+                    )
+                })
+                .map(|event| event.data.clone())
+                .collect(),
+        )
+    }
+
+    pub fn observe(&mut self, data: SessionData) -> Result<(), Error> {
+        match self {
+            Self::Record(recorder) => recorder.borrow_mut().record(data),
+            Self::Replay(expected) => match expected.pop_front() {
+                Some(expected) if expected == data => Ok(()),
+                expected => Err(Error::SessionDivergence {
+                    expected: expected.map_or("end of session".to_owned(), |e| format!("{e:?}")),
+                    found: format!("{data:?}"),
+                }),
+            },
+        }
+    }
+
+    pub fn finish(self) -> Result<(), Error> {
+        match self {
+            Self::Replay(mut expected) if !expected.is_empty() => Err(Error::SessionDivergence {
+                expected: format!("{:?}", expected.pop_front().unwrap()),
+                found: "end of session".to_owned(),
+            }),
+            _ => Ok(()),
+        }
+    }
+}
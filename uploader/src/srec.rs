@@ -0,0 +1,166 @@
+use std::io::Write;
+
+use crate::{
+    core::{ADDRESS_SPACE, SrecRecordType},
+    error::Error,
+};
+
+/// Parses a Motorola S-record file into a contiguous image starting at
+/// address 0. Gaps between records are filled with 0xFF.
+pub fn parse(text: &str) -> Result<Vec<u8>, Error> {
+    let mut data = vec![];
+
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+
+        if line.is_empty() {
+            continue;
+        }
+
+        let invalid = |reason: &str| Error::InvalidSrecRecord {
+            line: i + 1,
+            reason: reason.to_owned(),
+        };
+
+        let mut chars = line.chars();
+
+        if chars.next() != Some('S') {
+            return Err(invalid("missing 'S' start code"));
+        }
+
+        let kind = chars
+            .next()
+            .and_then(|c| c.to_digit(10))
+            .ok_or_else(|| invalid("missing record type"))?;
+
+        let digits = chars.as_str();
+
+        if digits.len() % 2 != 0 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(invalid("expected an even number of hex digits"));
+        }
+
+        let bytes: Vec<u8> = (0..digits.len())
+            .step_by(2)
+            .map(|j| u8::from_str_radix(&digits[j..j + 2], 16).unwrap())
+            .collect();
+
+        let Some((&count, rest)) = bytes.split_first() else {
+            return Err(invalid("record is too short"));
+        };
+
+        if rest.len() != usize::from(count) {
+            return Err(invalid("byte count does not match the record's length"));
+        }
+
+        if bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) != 0xFF {
+            return Err(invalid("checksum mismatch"));
+        }
+
+        let address_len = match kind {
+            0 | 1 | 5 | 9 => 2,
+            2 | 6 | 8 => 3,
+            3 | 7 => 4,
+            _ => return Err(invalid(&format!("unknown record type S{}", kind))),
+        };
+
+        // Without the checksum at the end
+        let rest = &rest[..rest.len().saturating_sub(1)];
+
+        if rest.len() < address_len {
+            return Err(invalid("record is too short for its address"));
+        }
+
+        let (address, payload) = rest.split_at(address_len);
+        let address = address
+            .iter()
+            .fold(0, |address, &b| (address << 8) | usize::from(b));
+
+        match kind {
+            1..=3 => {
+                let end = address + payload.len();
+
+                if end > ADDRESS_SPACE {
+                    return Err(Error::FileTooLargeForAddress {
+                        file_size: end,
+                        address_space: ADDRESS_SPACE,
+                    });
+                }
+
+                if end > data.len() {
+                    data.resize(end, 0xFF);
+                }
+
+                data[address..end].copy_from_slice(payload);
+            }
+            7..=9 => return Ok(data),
+            // Headers and record counts say nothing about the data
+            _ => {}
+        }
+    }
+
+    Err(Error::InvalidSrecRecord {
+        line: text.lines().count(),
+        reason: "missing termination record".to_owned(),
+    })
+}
+
+/// Writes `data` as records of `record_type`, each with up to `record_size`
+/// bytes, starting at `address`.
+pub fn write_data(
+    out: &mut impl Write,
+    address: u16,
+    data: &[u8],
+    record_size: u8,
+    record_type: SrecRecordType,
+) -> std::io::Result<()> {
+    let (kind, _, address_len) = record_kinds(record_type);
+    // The byte count also covers the address and the checksum
+    let max_size = u8::MAX - address_len as u8 - 1;
+    let record_size = record_size.clamp(1, max_size).into();
+
+    for (i, chunk) in data.chunks(record_size).enumerate() {
+        let address = address.wrapping_add((i * record_size) as u16);
+        write_record(out, kind, address.into(), address_len, chunk)?;
+    }
+
+    Ok(())
+}
+
+pub fn write_termination(out: &mut impl Write, record_type: SrecRecordType) -> std::io::Result<()> {
+    let (_, kind, address_len) = record_kinds(record_type);
+    write_record(out, kind, 0x0000, address_len, &[])
+}
+
+/// The digits of the data and termination records of `record_type`, and the
+/// length of their addresses in bytes.
+fn record_kinds(record_type: SrecRecordType) -> (u8, u8, usize) {
+    match record_type {
+        SrecRecordType::S1 => (1, 9, 2),
+        SrecRecordType::S2 => (2, 8, 3),
+        SrecRecordType::S3 => (3, 7, 4),
+    }
+}
+
+fn write_record(
+    out: &mut impl Write,
+    kind: u8,
+    address: u32,
+    address_len: usize,
+    data: &[u8],
+) -> std::io::Result<()> {
+    let address = &address.to_be_bytes()[4 - address_len..];
+    let count = (address_len + data.len() + 1) as u8;
+
+    let sum = address
+        .iter()
+        .chain(data)
+        .fold(count, |sum, &b| sum.wrapping_add(b));
+
+    write!(out, "S{}{:02X}", kind, count)?;
+
+    for byte in address.iter().chain(data) {
+        write!(out, "{:02X}", byte)?;
+    }
+
+    writeln!(out, "{:02X}", !sum)
+}
@@ -0,0 +1,78 @@
+//! Helpers for driving the [`State`](crate::State) machine in tests without a
+//! board.
+
+use std::collections::VecDeque;
+
+use crate::serial::SerialIO;
+
+/// What the board sends at power-up to get through protocol negotiation:
+/// Ready, Ack and VersionOk, followed by the Ready that starts the operation.
+pub const HANDSHAKE: [u8; 4] = [0x00, 0x09, 0x0B, 0x00];
+
+/// A [`SerialIO`] that plays back scripted board output and records what the
+/// host sends. Running out of scripted output panics, since a test that reads
+/// past it is waiting for a board that will never answer.
+#[derive(Debug, Default)]
+pub struct MockSerialIO {
+    reads: VecDeque<u8>,
+    pub writes: Vec<u8>,
+}
+
+impl MockSerialIO {
+    pub fn new(reads: &[u8]) -> Self {
+        Self {
+            reads: reads.iter().copied().collect(),
+            writes: vec![],
+        }
+    }
+
+    /// Board output that was never read.
+    pub fn unread(&self) -> usize {
+        self.reads.len()
+    }
+
+    fn take(&mut self, n: usize) -> Vec<u8> {
+        assert!(
+            self.reads.len() >= n,
+            "host read {} bytes, but only {} were scripted",
+            n,
+            self.reads.len()
+        );
+
+        self.reads.drain(..n).collect()
+    }
+}
+
+impl SerialIO for MockSerialIO {
+    fn read_u8(&mut self) -> anyhow::Result<u8> {
+        Ok(self.take(1)[0])
+    }
+
+    fn read_u16(&mut self) -> anyhow::Result<u16> {
+        let bytes = self.take(2);
+        Ok(self.byte_order().decode([bytes[0], bytes[1]]))
+    }
+
+    fn read_n(&mut self, n: usize) -> anyhow::Result<Vec<u8>> {
+        Ok(self.take(n))
+    }
+
+    fn read_available(&mut self, max: usize) -> anyhow::Result<Vec<u8>> {
+        Ok(self.take(max.min(self.reads.len())))
+    }
+
+    fn write_u8(&mut self, value: u8) -> anyhow::Result<()> {
+        self.writes.push(value);
+        Ok(())
+    }
+
+    fn write_u16(&mut self, value: u16) -> anyhow::Result<()> {
+        self.writes.extend(self.byte_order().encode(value));
+        Ok(())
+    }
+
+    fn write_n(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        self.writes.extend(data);
+        Ok(())
+    }
+}
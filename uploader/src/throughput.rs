@@ -0,0 +1,52 @@
+use std::{collections::VecDeque, time::Instant};
+
+/// Throughput averaged over the last few progress updates, so that it tracks
+/// the current speed instead of the whole operation's.
+#[derive(Debug)]
+pub struct RollingThroughput {
+    history: VecDeque<(Instant, usize)>,
+    window: usize,
+}
+
+impl RollingThroughput {
+    pub fn new(window: usize) -> Self {
+        // At least two samples are needed to measure anything
+        let window = window.max(2);
+
+        Self {
+            history: VecDeque::with_capacity(window),
+            window,
+        }
+    }
+
+    /// Records that `bytes` bytes are done, returning the current throughput
+    /// in bytes per second.
+    pub fn update(&mut self, bytes: usize) -> f64 {
+        // Progress going backwards means a new phase started
+        if self.history.back().is_some_and(|&(_, last)| bytes < last) {
+            self.history.clear();
+        }
+
+        if self.history.len() == self.window {
+            self.history.pop_front();
+        }
+
+        self.history.push_back((Instant::now(), bytes));
+
+        match (self.history.front(), self.history.back()) {
+            (Some(&(start, first)), Some(&(end, last))) if end > start => {
+                (last - first) as f64 / (end - start).as_secs_f64()
+            }
+            _ => 0.0,
+        }
+    }
+}
+
+/// Formats a transfer rate given in bytes per second.
+pub fn format_rate(bytes_per_sec: f64) -> String {
+    if bytes_per_sec >= 1024.0 {
+        format!("{:.1} KB/s", bytes_per_sec / 1024.0)
+    } else {
+        format!("{:.0} B/s", bytes_per_sec)
+    }
+}
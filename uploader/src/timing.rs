@@ -0,0 +1,100 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use crate::serial::{ByteOrder, SerialIO};
+
+/// Wraps a [`SerialIO`], writing how long each chunk transfer takes to a CSV
+/// file with the columns `chunk_index,direction,bytes,elapsed_us`.
+#[derive(Debug)]
+pub struct TimingSerialIO<IO: SerialIO> {
+    inner: IO,
+    out: BufWriter<File>,
+    chunk_index: usize,
+}
+
+impl<IO: SerialIO> TimingSerialIO<IO> {
+    pub fn new(inner: IO, path: &Path) -> std::io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+        writeln!(out, "chunk_index,direction,bytes,elapsed_us")?;
+
+        Ok(Self {
+            inner,
+            out,
+            chunk_index: 0,
+        })
+    }
+
+    fn report(&mut self, direction: &str, bytes: usize, start: Instant) -> anyhow::Result<()> {
+        writeln!(
+            self.out,
+            "{},{},{},{}",
+            self.chunk_index,
+            direction,
+            bytes,
+            start.elapsed().as_micros()
+        )?;
+        self.out.flush()?;
+        self.chunk_index += 1;
+
+        Ok(())
+    }
+}
+
+impl<IO: SerialIO> SerialIO for TimingSerialIO<IO> {
+    fn read_u8(&mut self) -> anyhow::Result<u8> {
+        self.inner.read_u8()
+    }
+
+    fn read_u16(&mut self) -> anyhow::Result<u16> {
+        self.inner.read_u16()
+    }
+
+    fn read_n(&mut self, n: usize) -> anyhow::Result<Vec<u8>> {
+        let start = Instant::now();
+        let data = self.inner.read_n(n)?;
+        self.report("rx", data.len(), start)?;
+        Ok(data)
+    }
+
+    fn read_available(&mut self, max: usize) -> anyhow::Result<Vec<u8>> {
+        self.inner.read_available(max)
+    }
+
+    fn write_u8(&mut self, value: u8) -> anyhow::Result<()> {
+        self.inner.write_u8(value)
+    }
+
+    fn write_u16(&mut self, value: u16) -> anyhow::Result<()> {
+        self.inner.write_u16(value)
+    }
+
+    fn write_n(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        let start = Instant::now();
+        self.inner.write_n(data)?;
+        self.report("tx", data.len(), start)
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.inner.flush()
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> anyhow::Result<()> {
+        self.inner.set_timeout(timeout)
+    }
+
+    fn take_slow_reads(&mut self) -> Vec<Duration> {
+        self.inner.take_slow_reads()
+    }
+
+    fn reconnect(&mut self) -> anyhow::Result<()> {
+        self.inner.reconnect()
+    }
+
+    fn byte_order(&self) -> ByteOrder {
+        self.inner.byte_order()
+    }
+}
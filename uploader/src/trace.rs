@@ -0,0 +1,96 @@
+use std::time::Duration;
+
+use crate::{
+    display,
+    serial::{ByteOrder, SerialIO},
+};
+
+/// Target of the events emitted by [`TracingSerialIO`], so that they can be
+/// enabled on their own.
+pub const TARGET: &str = "wire";
+
+/// Wraps a [`SerialIO`], emitting a trace event with the hex representation of
+/// every value that crosses the wire.
+///
+/// Reads done while parsing a packet happen inside the `read_packet` span, so
+/// the packet's name shows up next to its bytes.
+#[derive(Debug)]
+pub struct TracingSerialIO<IO: SerialIO> {
+    inner: IO,
+}
+
+impl<IO: SerialIO> TracingSerialIO<IO> {
+    pub fn new(inner: IO) -> Self {
+        Self { inner }
+    }
+}
+
+fn format_bytes(data: &[u8]) -> String {
+    format!("{} ({} bytes)", display::hex_bytes(data), data.len())
+}
+
+impl<IO: SerialIO> SerialIO for TracingSerialIO<IO> {
+    fn read_u8(&mut self) -> anyhow::Result<u8> {
+        let value = self.inner.read_u8()?;
+        tracing::trace!(target: TARGET, "← 0x{:02X}", value);
+        Ok(value)
+    }
+
+    fn read_u16(&mut self) -> anyhow::Result<u16> {
+        let value = self.inner.read_u16()?;
+        tracing::trace!(target: TARGET, "← 0x{:04X}", value);
+        Ok(value)
+    }
+
+    fn read_n(&mut self, n: usize) -> anyhow::Result<Vec<u8>> {
+        let data = self.inner.read_n(n)?;
+        tracing::trace!(target: TARGET, "← {}", format_bytes(&data));
+        Ok(data)
+    }
+
+    fn read_available(&mut self, max: usize) -> anyhow::Result<Vec<u8>> {
+        let data = self.inner.read_available(max)?;
+
+        if !data.is_empty() {
+            tracing::trace!(target: TARGET, "← {}", format_bytes(&data));
+        }
+
+        Ok(data)
+    }
+
+    fn write_u8(&mut self, value: u8) -> anyhow::Result<()> {
+        tracing::trace!(target: TARGET, "→ 0x{:02X}", value);
+        self.inner.write_u8(value)
+    }
+
+    fn write_u16(&mut self, value: u16) -> anyhow::Result<()> {
+        tracing::trace!(target: TARGET, "→ 0x{:04X}", value);
+        self.inner.write_u16(value)
+    }
+
+    fn write_n(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        tracing::trace!(target: TARGET, "→ {}", format_bytes(data));
+        self.inner.write_n(data)
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.inner.flush()
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> anyhow::Result<()> {
+        self.inner.set_timeout(timeout)
+    }
+
+    fn take_slow_reads(&mut self) -> Vec<Duration> {
+        self.inner.take_slow_reads()
+    }
+
+    fn reconnect(&mut self) -> anyhow::Result<()> {
+        tracing::trace!(target: TARGET, "reconnecting");
+        self.inner.reconnect()
+    }
+
+    fn byte_order(&self) -> ByteOrder {
+        self.inner.byte_order()
+    }
+}
@@ -0,0 +1,214 @@
+use std::{collections::VecDeque, time::Instant};
+
+use ratatui::{
+    DefaultTerminal, Frame,
+    layout::{Constraint, Layout},
+    style::{Color, Style},
+    text::Line,
+    widgets::{Block, Gauge, Paragraph},
+};
+
+use eeprom_programmer::{core::Effect, throughput::RollingThroughput};
+
+use crate::TransferStats;
+
+/// How many lines the log panel remembers.
+const LOG_LINES: usize = 200;
+
+/// Renders effects as a full-screen terminal UI instead of plain output.
+#[derive(Debug)]
+pub struct Tui {
+    terminal: DefaultTerminal,
+    view: View,
+}
+
+#[derive(Debug)]
+struct View {
+    baud_rate: u32,
+    start: Instant,
+    done: usize,
+    total: usize,
+    throughput: RollingThroughput,
+    rate: f64,
+    status: String,
+    log: VecDeque<String>,
+    partial_line: String,
+}
+
+impl Tui {
+    pub fn new(baud_rate: u32, progress_history: usize) -> std::io::Result<Self> {
+        Ok(Self {
+            terminal: ratatui::try_init()?,
+            view: View {
+                baud_rate,
+                start: Instant::now(),
+                done: 0,
+                total: 0,
+                throughput: RollingThroughput::new(progress_history),
+                rate: 0.0,
+                status: "Waiting for the board...".to_owned(),
+                log: VecDeque::new(),
+                partial_line: String::new(),
+            },
+        })
+    }
+
+    pub fn render(&mut self, effect: &Effect, stats: &TransferStats) -> std::io::Result<()> {
+        self.view.update(effect);
+
+        let mismatches = match effect {
+            Effect::VerifyProgress { mismatches, .. } => *mismatches,
+            _ => stats.mismatches,
+        };
+
+        self.terminal
+            .draw(|frame| self.view.draw(frame, stats, mismatches))?;
+
+        Ok(())
+    }
+}
+
+impl View {
+    fn update(&mut self, effect: &Effect) {
+        match effect {
+            Effect::PrintLn(s) => self.status = s.clone(),
+            Effect::Print(s) => self.push_board_output(s),
+            Effect::Progress { done, total }
+            | Effect::VerifyProgress { done, total, .. }
+            | Effect::WriteProgress {
+                written: done,
+                total,
+                ..
+            }
+            | Effect::FixingByte {
+                current: done,
+                total,
+                ..
+            } => {
+                self.done = *done;
+                self.total = *total;
+                self.rate = self.throughput.update(*done);
+            }
+            Effect::Erasing { elapsed } => {
+                self.status = format!("Erasing... ({}s)", elapsed.as_secs());
+            }
+            Effect::Warning(s) => self.push_log(format!("Warning: {}", s)),
+            Effect::Alert(s) => self.push_log(format!("ALERT: {}", s)),
+            Effect::OperationWarning(s) => self.push_log(format!("[WARNING] {}", s)),
+            Effect::WriteSummary {
+                written,
+                verified,
+                fixed,
+            } => {
+                self.status = format!(
+                    "Wrote {} bytes, verified {} and fixed {}",
+                    written, verified, fixed
+                );
+            }
+            Effect::DryRunSummary {
+                file_size,
+                checksum,
+            } => {
+                self.status = format!("Dry run: {} bytes, checksum 0x{:04X}", file_size, checksum);
+            }
+            Effect::TransferSummary {
+                operation,
+                bytes,
+                duration_ms,
+                checksum,
+                retries,
+            } => self.push_log(format!(
+                "{:?}: {} bytes in {} ms, checksum 0x{:04X}, {} retries",
+                operation, bytes, duration_ms, checksum, retries
+            )),
+            Effect::MismatchReport { mismatches } => {
+                for mismatch in mismatches {
+                    self.push_log(format!(
+                        "Mismatch at 0x{:04X}: expected 0x{:02X}, found 0x{:02X}",
+                        mismatch.address, mismatch.expected, mismatch.found
+                    ));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn push_board_output(&mut self, s: &str) {
+        self.partial_line.push_str(s);
+
+        while let Some(i) = self.partial_line.find('\n') {
+            let line = self.partial_line[..i].trim_end_matches('\r').to_owned();
+            self.partial_line.drain(..=i);
+            self.push_log(line);
+        }
+    }
+
+    fn push_log(&mut self, line: String) {
+        if self.log.len() == LOG_LINES {
+            self.log.pop_front();
+        }
+
+        self.log.push_back(line);
+    }
+
+    fn draw(&self, frame: &mut Frame, stats: &TransferStats, mismatches: usize) {
+        let [progress_area, log_area, stats_area] = Layout::vertical([
+            Constraint::Length(3),
+            Constraint::Min(3),
+            Constraint::Length(3),
+        ])
+        .areas(frame.area());
+
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let rate = self.rate;
+
+        let ratio = if self.total == 0 {
+            0.0
+        } else {
+            self.done as f64 / self.total as f64
+        };
+
+        let eta = if rate > 0.0 && self.done < self.total {
+            format!("{:.0}s", (self.total - self.done) as f64 / rate)
+        } else {
+            "-".to_owned()
+        };
+
+        frame.render_widget(
+            Gauge::default()
+                .block(Block::bordered().title(self.status.as_str()))
+                .gauge_style(Style::default().fg(Color::Green))
+                .ratio(ratio.clamp(0.0, 1.0))
+                .label(format!("{:.0}% (ETA {})", ratio * 100.0, eta)),
+            progress_area,
+        );
+
+        let visible = usize::from(log_area.height.saturating_sub(2));
+        let lines: Vec<Line> = self
+            .log
+            .iter()
+            .skip(self.log.len().saturating_sub(visible))
+            .map(|line| Line::raw(line.as_str()))
+            .collect();
+
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title("Log")),
+            log_area,
+        );
+
+        frame.render_widget(
+            Paragraph::new(format!(
+                "Baud rate: {}  |  {:.0} B/s  |  Retries: {}  |  Mismatches: {}  |  Elapsed: {:.1}s",
+                self.baud_rate, rate, stats.retries, mismatches, elapsed
+            ))
+            .block(Block::bordered().title("Stats")),
+            stats_area,
+        );
+    }
+}
+
+impl Drop for Tui {
+    fn drop(&mut self) {
+        ratatui::restore();
+    }
+}
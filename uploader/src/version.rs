@@ -0,0 +1,162 @@
+use std::{cmp::Ordering, str::FromStr};
+
+use derive_more::Display;
+use serde::Deserialize;
+
+/// Version of the firmware running on the board.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Display)]
+#[display("{major}.{minor}.{patch}")]
+pub struct FirmwareVersion {
+    pub major: u8,
+    pub minor: u8,
+    pub patch: u8,
+}
+
+impl FirmwareVersion {
+    fn as_tuple(self) -> (u16, u16, u16) {
+        (self.major.into(), self.minor.into(), self.patch.into())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Exact,
+    Greater,
+    GreaterEq,
+    Less,
+    LessEq,
+    /// Compatible updates: anything up to the next change of the leftmost
+    /// non-zero part, as in Cargo.
+    Caret,
+    /// Patch updates only, or minor ones if only the major part is given.
+    Tilde,
+}
+
+/// A single constraint such as `>=1.2`. Missing parts count as zero, but are
+/// remembered for the operators whose meaning depends on them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Comparator {
+    op: Op,
+    version: FirmwareVersion,
+    parts: usize,
+}
+
+impl Comparator {
+    /// Smallest version past the range allowed by `=`, `^` and `~`, as a
+    /// tuple so that it can go past 255.
+    fn upper_bound(&self) -> (u16, u16, u16) {
+        let (major, minor, patch) = self.version.as_tuple();
+
+        match (self.op, self.parts) {
+            (Op::Caret, _) if major > 0 || self.parts == 1 => (major + 1, 0, 0),
+            (Op::Caret, _) if minor > 0 || self.parts == 2 => (0, minor + 1, 0),
+            (Op::Caret, _) => (0, 0, patch + 1),
+            (Op::Tilde | Op::Exact, 1) => (major + 1, 0, 0),
+            (Op::Tilde | Op::Exact, 2) | (Op::Tilde, _) => (major, minor + 1, 0),
+            _ => (major, minor, patch + 1),
+        }
+    }
+
+    fn matches(&self, version: FirmwareVersion) -> bool {
+        let ordering = version.cmp(&self.version);
+
+        match self.op {
+            Op::Greater => ordering == Ordering::Greater,
+            Op::GreaterEq => ordering != Ordering::Less,
+            Op::Less => ordering == Ordering::Less,
+            Op::LessEq => ordering != Ordering::Greater,
+            Op::Exact | Op::Caret | Op::Tilde => {
+                ordering != Ordering::Less && version.as_tuple() < self.upper_bound()
+            }
+        }
+    }
+}
+
+impl FromStr for Comparator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+
+        let (op, rest) = [
+            (">=", Op::GreaterEq),
+            ("<=", Op::LessEq),
+            (">", Op::Greater),
+            ("<", Op::Less),
+            ("=", Op::Exact),
+            ("^", Op::Caret),
+            ("~", Op::Tilde),
+        ]
+        .into_iter()
+        .find_map(|(prefix, op)| s.strip_prefix(prefix).map(|rest| (op, rest)))
+        .unwrap_or((Op::Caret, s));
+
+        let parts = rest
+            .trim()
+            .split('.')
+            .map(|part| {
+                part.parse::<u8>()
+                    .map_err(|_| format!("invalid version '{}'", rest.trim()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if parts.is_empty() || parts.len() > 3 {
+            return Err(format!("invalid version '{}'", rest.trim()));
+        }
+
+        let part = |i: usize| parts.get(i).copied().unwrap_or(0);
+
+        Ok(Self {
+            op,
+            version: FirmwareVersion {
+                major: part(0),
+                minor: part(1),
+                patch: part(2),
+            },
+            parts: parts.len(),
+        })
+    }
+}
+
+/// Versions of the firmware the uploader is willing to talk to, given as
+/// comma-separated constraints such as `>=1.2, <2`. A bare version means
+/// `^version`.
+#[derive(Debug, Clone, PartialEq, Eq, Display, Deserialize)]
+#[display("{text}")]
+#[serde(try_from = "String")]
+pub struct VersionReq {
+    text: String,
+    comparators: Vec<Comparator>,
+}
+
+impl VersionReq {
+    pub fn matches(&self, version: FirmwareVersion) -> bool {
+        self.comparators
+            .iter()
+            .all(|comparator| comparator.matches(version))
+    }
+}
+
+impl FromStr for VersionReq {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let comparators = s
+            .split(',')
+            .map(Comparator::from_str)
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            text: s.trim().to_owned(),
+            comparators,
+        })
+    }
+}
+
+impl TryFrom<String> for VersionReq {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
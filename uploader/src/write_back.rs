@@ -0,0 +1,81 @@
+use crate::{
+    core::{DEFAULT_WRITE_CHUNK_SIZE, StateKind},
+    error::Error,
+    protocol::{self, Packet},
+    serial::SerialIO,
+};
+
+/// Forwards data read from one EEPROM to a second board, which writes it to
+/// its own EEPROM starting at address 0.
+#[derive(Debug)]
+pub struct WriteBack<IO: SerialIO> {
+    port: IO,
+}
+
+impl<IO: SerialIO> WriteBack<IO> {
+    pub fn start(mut port: IO) -> Result<Self, Error> {
+        loop {
+            match protocol::read_packet(&mut port)? {
+                Packet::Ready => break,
+                Packet::Print(_) => {}
+                packet => {
+                    return Err(Error::UnexpectedPacket {
+                        state_kind: StateKind::Idle,
+                        packet,
+                    });
+                }
+            }
+        }
+
+        protocol::send_command(&mut port, 0x01)?;
+        port.write_u8(0x00)?;
+        port.write_u8(0x00)?;
+        port.flush()?;
+
+        Ok(Self { port })
+    }
+
+    pub fn write_chunk(&mut self, chunk: &[u8]) -> Result<(), Error> {
+        let mut current_byte = 0;
+
+        while current_byte < chunk.len() {
+            self.wait_for_chunk_request()?;
+            protocol::send_data_chunk(
+                &mut self.port,
+                chunk,
+                &mut current_byte,
+                DEFAULT_WRITE_CHUNK_SIZE.into(),
+                false,
+            )?;
+            self.port.flush()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<(), Error> {
+        self.wait_for_chunk_request()?;
+        self.port.write_u8(0x00)?;
+        self.port.flush()?;
+
+        Ok(())
+    }
+
+    fn wait_for_chunk_request(&mut self) -> Result<(), Error> {
+        loop {
+            match protocol::read_packet(&mut self.port)? {
+                Packet::ChunkRequest => return Ok(()),
+                Packet::Print(_) => {}
+                Packet::InvalidChecksum { expected, computed } => {
+                    return Err(Error::ChecksumMismatch { expected, computed });
+                }
+                packet => {
+                    return Err(Error::UnexpectedPacket {
+                        state_kind: StateKind::Writing,
+                        packet,
+                    });
+                }
+            }
+        }
+    }
+}
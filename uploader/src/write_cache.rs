@@ -0,0 +1,198 @@
+use std::{
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{checksum::ChecksumAlgorithm, chips::Chip, error::Error};
+
+/// A write that went through. Entries from older caches lack the chip and
+/// range, so they never match.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WriteCacheEntry {
+    pub path: PathBuf,
+    /// Hash of the image that was written, after every option that shapes it
+    /// was applied to the input file.
+    pub sha256: String,
+    #[serde(default)]
+    pub chip: String,
+    /// First EEPROM address written.
+    #[serde(default)]
+    pub start: usize,
+    /// One past the last EEPROM address written.
+    #[serde(default)]
+    pub end: usize,
+    pub timestamp: u64,
+}
+
+impl WriteCacheEntry {
+    /// Describes writing `image` to `chip`, starting at address `start`.
+    pub fn new(path: &Path, image: &[u8], chip: Chip, start: usize) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        Self {
+            path: path.to_path_buf(),
+            sha256: ChecksumAlgorithm::Sha256.compute(image),
+            chip: chip.name().to_owned(),
+            start,
+            end: start + image.len(),
+            timestamp,
+        }
+    }
+
+    /// Whether both writes touched some of the same addresses of the same chip.
+    fn overlaps(&self, other: &Self) -> bool {
+        self.chip == other.chip && self.start < other.end && other.start < self.end
+    }
+
+    fn matches(&self, other: &Self) -> bool {
+        self.path == other.path
+            && self.sha256 == other.sha256
+            && self.chip == other.chip
+            && self.start == other.start
+            && self.end == other.end
+    }
+}
+
+/// Remembers which files were successfully written (and verified), so that
+/// writing them again can be skipped.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WriteCache {
+    entries: Vec<WriteCacheEntry>,
+}
+
+impl WriteCache {
+    /// Loads the cache at `path`, which may not exist yet.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        match std::fs::read_to_string(path) {
+            Ok(contents) => Ok(serde_json::from_str(&contents)?),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn contains(&self, entry: &WriteCacheEntry) -> bool {
+        self.entries.iter().any(|e| e.matches(entry))
+    }
+
+    /// Records a successful write. The cache describes the chip in the socket,
+    /// so every earlier entry whose addresses the write overwrote is dropped,
+    /// whichever file it came from.
+    pub fn insert(&mut self, entry: WriteCacheEntry) {
+        self.invalidate(&entry);
+        self.entries.push(entry);
+    }
+
+    /// Forgets every write that `entry`'s addresses overlap, for when the chip
+    /// is about to be written with no guarantee of ending up cached.
+    pub fn invalidate(&mut self, entry: &WriteCacheEntry) {
+        self.entries.retain(|e| !e.overlaps(entry));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cache_matches_same_image_chip_and_range() {
+        let mut cache = WriteCache::default();
+        cache.insert(WriteCacheEntry::new(
+            Path::new("rom.bin"),
+            &[1, 2, 3],
+            Chip::At28c256,
+            0,
+        ));
+
+        let entry = WriteCacheEntry::new(Path::new("rom.bin"), &[1, 2, 3], Chip::At28c256, 0);
+        assert!(cache.contains(&entry));
+    }
+
+    #[test]
+    fn cache_misses_when_image_chip_or_range_differ() {
+        let mut cache = WriteCache::default();
+        cache.insert(WriteCacheEntry::new(
+            Path::new("rom.bin"),
+            &[1, 2, 3],
+            Chip::At28c256,
+            0,
+        ));
+
+        for entry in [
+            WriteCacheEntry::new(Path::new("rom.bin"), &[1, 2, 4], Chip::At28c256, 0),
+            WriteCacheEntry::new(Path::new("rom.bin"), &[1, 2, 3], Chip::At28c64, 0),
+            WriteCacheEntry::new(Path::new("rom.bin"), &[1, 2, 3], Chip::At28c256, 0x100),
+            WriteCacheEntry::new(Path::new("rom.bin"), &[1, 2, 3, 0xFF], Chip::At28c256, 0),
+        ] {
+            assert!(!cache.contains(&entry), "{:?}", entry);
+        }
+    }
+
+    #[test]
+    fn writing_another_file_invalidates_the_previous_one() {
+        let a = || WriteCacheEntry::new(Path::new("a.bin"), &[1, 2, 3], Chip::At28c256, 0);
+        let b = || WriteCacheEntry::new(Path::new("b.bin"), &[4, 5, 6], Chip::At28c256, 0);
+
+        let mut cache = WriteCache::default();
+        cache.insert(a());
+        cache.insert(b());
+
+        assert!(!cache.contains(&a()));
+        assert!(cache.contains(&b()));
+
+        cache.insert(a());
+
+        assert!(cache.contains(&a()));
+        assert!(!cache.contains(&b()));
+    }
+
+    #[test]
+    fn invalidating_forgets_overlapping_writes() {
+        let a = || WriteCacheEntry::new(Path::new("a.bin"), &[1, 2, 3], Chip::At28c256, 0);
+
+        let mut cache = WriteCache::default();
+        cache.insert(a());
+        cache.invalidate(&WriteCacheEntry::new(
+            Path::new("b.bin"),
+            &[4],
+            Chip::At28c256,
+            2,
+        ));
+
+        assert!(!cache.contains(&a()));
+    }
+
+    #[test]
+    fn writes_to_other_addresses_stay_cached() {
+        let low = || WriteCacheEntry::new(Path::new("a.bin"), &[1, 2], Chip::At28c256, 0);
+        let high = || WriteCacheEntry::new(Path::new("b.bin"), &[3, 4], Chip::At28c256, 2);
+
+        let mut cache = WriteCache::default();
+        cache.insert(low());
+        cache.insert(high());
+
+        assert!(cache.contains(&low()));
+        assert!(cache.contains(&high()));
+    }
+
+    #[test]
+    fn entries_from_older_caches_never_match() {
+        let cache: WriteCache = serde_json::from_str(&format!(
+            r#"{{"entries": [{{"path": "rom.bin", "sha256": "{}", "chip_size": 32768, "timestamp": 0}}]}}"#,
+            ChecksumAlgorithm::Sha256.compute(&[1, 2, 3])
+        ))
+        .unwrap();
+
+        let entry = WriteCacheEntry::new(Path::new("rom.bin"), &[1, 2, 3], Chip::At28c256, 0);
+        assert!(!cache.contains(&entry));
+    }
+}